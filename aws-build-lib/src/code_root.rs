@@ -0,0 +1,348 @@
+//! Work out which source paths need to be mounted in the container.
+
+use anyhow::{anyhow, Context, Error};
+use cargo_metadata::MetadataCommand;
+use fehler::{throw, throws};
+use fs_err as fs;
+use log::warn;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Get the directories of all the path dependencies of the workspace
+/// containing `project_path`, including indirect ones.
+///
+/// All workspace members are included since cargo needs every
+/// member's dependencies to load the workspace. Metadata is read
+/// without dependencies so that no network access is needed.
+#[throws]
+pub(crate) fn find_path_dependencies(project_path: &Path) -> Vec<PathBuf> {
+    let mut visited = BTreeSet::new();
+    let mut to_visit = vec![project_path.to_path_buf()];
+    let mut deps = BTreeSet::new();
+
+    while let Some(dir) = to_visit.pop() {
+        if !visited.insert(dir.clone()) {
+            continue;
+        }
+        let metadata = MetadataCommand::new()
+            .current_dir(&dir)
+            .no_deps()
+            .exec()
+            .with_context(|| {
+                format!("failed to read metadata of {}", dir.display())
+            })?;
+        for package in &metadata.packages {
+            // For the starting directory every workspace member is
+            // needed. For a dependency only its own package is.
+            let package_dir = package.manifest_path.parent().map(Path::new);
+            if dir != project_path && package_dir != Some(dir.as_path()) {
+                continue;
+            }
+            for dep in &package.dependencies {
+                if let Some(path) = &dep.path {
+                    let path = fs::canonicalize(path).with_context(|| {
+                        format!("invalid path dependency {}", dep.name)
+                    })?;
+                    deps.insert(path.clone());
+                    to_visit.push(path);
+                }
+            }
+        }
+    }
+    deps.into_iter().collect()
+}
+
+/// Get the deepest directory that contains both `a` and `b`.
+fn common_ancestor(a: &Path, b: &Path) -> PathBuf {
+    a.components()
+        .zip(b.components())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect()
+}
+
+/// Get the code root to mount in the container.
+///
+/// If `code_root` is set, every path dependency must be inside it.
+/// Otherwise the smallest directory containing the workspace root
+/// and every path dependency is used. All paths must be absolute.
+#[throws]
+pub(crate) fn choose_code_root(
+    code_root: Option<&Path>,
+    workspace_root: &Path,
+    path_deps: &[PathBuf],
+) -> PathBuf {
+    if let Some(code_root) = code_root {
+        for dep in path_deps {
+            if !dep.starts_with(code_root) {
+                throw!(anyhow!(
+                    "path dependency {} is outside the code root {}",
+                    dep.display(),
+                    code_root.display()
+                ));
+            }
+        }
+        return code_root.into();
+    }
+
+    let mut root = workspace_root.to_path_buf();
+    for dep in path_deps {
+        root = common_ancestor(&root, dep);
+        // Mounting the whole filesystem would defeat the purpose of
+        // the container.
+        if root.parent().is_none() {
+            throw!(anyhow!(
+                "path dependency {} has no common parent directory with {}, \
+                 it can't be mounted in the container",
+                dep.display(),
+                workspace_root.display()
+            ));
+        }
+    }
+    root
+}
+
+/// Remove paths that are inside another path in the list.
+fn remove_nested(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    // Sorting puts each path before any paths inside it
+    paths.sort();
+    paths.dedup();
+    let mut kept: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if !kept.iter().any(|parent| path.starts_with(parent)) {
+            kept.push(path);
+        }
+    }
+    kept
+}
+
+/// Names of the config files that cargo reads from `.cargo`
+/// directories.
+pub(crate) const CARGO_CONFIG_FILES: [&str; 2] = ["config", "config.toml"];
+
+/// Get the directories of the `directory` sources (e.g. vendored
+/// dependencies) named in the config files in `cargo_dir`. Relative
+/// paths are relative to the parent of `cargo_dir`. Directories that
+/// don't exist or are outside `code_root` are skipped, since they
+/// can't be mounted.
+#[throws]
+fn directory_sources(cargo_dir: &Path, code_root: &Path) -> Vec<PathBuf> {
+    let base = cargo_dir
+        .parent()
+        .ok_or_else(|| anyhow!("invalid cargo config directory"))?;
+    let mut dirs = Vec::new();
+    for name in CARGO_CONFIG_FILES {
+        let path = cargo_dir.join(name);
+        if !path.exists() {
+            continue;
+        }
+        let config: toml::Value =
+            fs::read_to_string(&path)?.parse().with_context(|| {
+                format!("invalid cargo config {}", path.display())
+            })?;
+        let sources = config
+            .get("source")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flat_map(|sources| sources.values());
+        for source in sources {
+            let dir = match source.get("directory").and_then(|dir| dir.as_str())
+            {
+                Some(dir) => base.join(dir),
+                None => continue,
+            };
+            match fs::canonicalize(&dir) {
+                Ok(dir) if dir.starts_with(code_root) => dirs.push(dir),
+                _ => warn!(
+                    "not mounting source directory {}, it must exist \
+                     within the code root",
+                    dir.display()
+                ),
+            }
+        }
+    }
+    dirs
+}
+
+/// Get the minimal set of host paths to mount for building the
+/// workspace containing `project_path`: the directory of each
+/// workspace member and path dependency, plus the workspace's
+/// `Cargo.toml`, `Cargo.lock`, and `.cargo` directory, and any
+/// directory sources in `code_root` that its cargo config names.
+///
+/// If the workspace root is itself a package, mounting its directory
+/// would mount the whole workspace, so only the top-level entries
+/// that contain its targets (e.g. `src` and `build.rs`) are mounted.
+#[throws]
+pub(crate) fn minimal_mounts(
+    project_path: &Path,
+    path_deps: &[PathBuf],
+    code_root: &Path,
+) -> Vec<PathBuf> {
+    let metadata = MetadataCommand::new()
+        .current_dir(project_path)
+        .no_deps()
+        .exec()?;
+    let metadata_root: &Path = metadata.workspace_root.as_ref();
+    let workspace_root = fs::canonicalize(metadata_root)?;
+
+    let mut paths = vec![workspace_root.join("Cargo.toml")];
+    let lock_path = workspace_root.join("Cargo.lock");
+    if lock_path.exists() {
+        paths.push(lock_path);
+    }
+    let cargo_dir = workspace_root.join(".cargo");
+    if cargo_dir.is_dir() {
+        paths.extend(directory_sources(&cargo_dir, code_root)?);
+        paths.push(cargo_dir);
+    }
+
+    for package in &metadata.packages {
+        let manifest_path: &Path = package.manifest_path.as_ref();
+        let package_dir = manifest_path
+            .parent()
+            .ok_or_else(|| anyhow!("invalid manifest path"))?;
+        if package_dir != metadata_root {
+            paths.push(fs::canonicalize(package_dir)?);
+            continue;
+        }
+        for target in &package.targets {
+            let src_path: &Path = target.src_path.as_ref();
+            if let Some(first) = src_path
+                .strip_prefix(package_dir)
+                .ok()
+                .and_then(|path| path.components().next())
+            {
+                paths.push(workspace_root.join(first));
+            }
+        }
+    }
+
+    paths.extend(path_deps.iter().cloned());
+    remove_nested(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_code_root() {
+        let ws = Path::new("/src/repo/ws");
+        let deps = [
+            PathBuf::from("/src/repo/ws/member"),
+            PathBuf::from("/src/repo/shared/dep"),
+        ];
+
+        assert_eq!(choose_code_root(None, ws, &[]).unwrap(), ws);
+        assert_eq!(
+            choose_code_root(None, ws, &deps).unwrap(),
+            Path::new("/src/repo")
+        );
+        assert_eq!(
+            choose_code_root(Some(Path::new("/src")), ws, &deps).unwrap(),
+            Path::new("/src")
+        );
+
+        let err = choose_code_root(Some(ws), ws, &deps).unwrap_err();
+        assert!(err.to_string().contains("/src/repo/shared/dep"));
+
+        let err =
+            choose_code_root(None, ws, &[PathBuf::from("/other")]).unwrap_err();
+        assert!(err.to_string().contains("/other"));
+    }
+
+    #[test]
+    fn test_remove_nested() {
+        let paths = |paths: &[&str]| {
+            paths.iter().map(PathBuf::from).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            remove_nested(paths(&["/a/b", "/c", "/a", "/a/b/c", "/ab", "/c"])),
+            paths(&["/a", "/ab", "/c"])
+        );
+    }
+
+    #[test]
+    fn test_minimal_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("member/src")).unwrap();
+        fs::create_dir_all(root.join("secrets")).unwrap();
+        fs::write(root.join("src/main.rs"), "").unwrap();
+        fs::write(root.join("member/src/lib.rs"), "").unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            "[package]\nname = \"root\"\nversion = \"0.0.0\"\n\
+             [workspace]\nmembers = [\"member\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("member/Cargo.toml"),
+            "[package]\nname = \"member\"\nversion = \"0.0.0\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            minimal_mounts(&root, &[PathBuf::from("/elsewhere/dep")], &root)
+                .unwrap(),
+            vec![
+                PathBuf::from("/elsewhere/dep"),
+                root.join("Cargo.toml"),
+                root.join("member"),
+                root.join("src"),
+            ]
+        );
+
+        // The workspace's cargo config is mounted, along with the
+        // vendored sources it names
+        fs::create_dir_all(root.join(".cargo")).unwrap();
+        fs::create_dir_all(root.join("vendor")).unwrap();
+        fs::write(
+            root.join(".cargo/config.toml"),
+            "[source.crates-io]\nreplace-with = \"vendored\"\n\
+             [source.vendored]\ndirectory = \"vendor\"\n\
+             [source.outside]\ndirectory = \"/nonexistent\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            minimal_mounts(&root, &[], &root).unwrap(),
+            vec![
+                root.join(".cargo"),
+                root.join("Cargo.toml"),
+                root.join("member"),
+                root.join("src"),
+                root.join("vendor"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_path_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let write_package = |name: &str, deps: &str| {
+            let path = root.join(name);
+            fs::create_dir_all(path.join("src")).unwrap();
+            fs::write(path.join("src/lib.rs"), "").unwrap();
+            fs::write(
+                path.join("Cargo.toml"),
+                format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.0.0\"\n\
+                     [dependencies]\n{}\n[workspace]\n",
+                    name, deps
+                ),
+            )
+            .unwrap();
+        };
+        write_package("a", r#"b = { path = "../b" }"#);
+        write_package("b", r#"c = { path = "../c" }"#);
+        write_package("c", "");
+
+        assert_eq!(
+            find_path_dependencies(&root.join("a")).unwrap(),
+            vec![root.join("b"), root.join("c")]
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! Package Amazon Linux 2 outputs as CodeDeploy bundles.
+
+use anyhow::{anyhow, Context, Error};
+use cargo_metadata::Package;
+use fehler::{throw, throws};
+use fs_err as fs;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zip::ZipWriter;
+
+/// Name of the directory in the mode's output directory where
+/// bundles are written.
+pub(crate) const BUNDLE_DIR: &str = "codedeploy";
+
+/// Lifecycle events that a bundle can have hooks for, in the order
+/// CodeDeploy runs them.
+const EVENTS: [&str; 5] = [
+    "ApplicationStop",
+    "BeforeInstall",
+    "AfterInstall",
+    "ApplicationStart",
+    "ValidateService",
+];
+
+/// Deployment settings read from `[package.metadata.aws-build]`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct DeployConfig {
+    /// Description of the service.
+    description: String,
+
+    /// Directory the binary is installed to.
+    install_dir: String,
+
+    /// User the service runs as, or root if not set.
+    user: Option<String>,
+
+    /// Arguments passed to the binary.
+    args: Vec<String>,
+
+    /// Environment variables set for the service.
+    environment: BTreeMap<String, String>,
+
+    /// Systemd restart policy.
+    restart: String,
+
+    /// URL that must respond successfully for the deployment to be
+    /// considered healthy.
+    health_check_url: Option<String>,
+
+    /// Extra hook scripts for each lifecycle event, relative to the
+    /// package directory. They run after the generated hooks.
+    hooks: BTreeMap<String, Vec<PathBuf>>,
+}
+
+/// A string or list of strings in the package metadata.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StrList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StrList {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            StrList::One(s) => vec![s],
+            StrList::Many(list) => list,
+        }
+    }
+}
+
+/// The `[package.metadata.aws-build]` table as written, before
+/// defaults are applied.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct Metadata {
+    description: Option<String>,
+    install_dir: Option<String>,
+    user: Option<String>,
+    args: Option<StrList>,
+    environment: BTreeMap<String, String>,
+    restart: Option<String>,
+    health_check_url: Option<String>,
+    hooks: BTreeMap<String, StrList>,
+}
+
+impl DeployConfig {
+    /// Read the settings for `bin` from the package metadata. All
+    /// settings are optional:
+    ///
+    /// ```toml
+    /// [package.metadata.aws-build]
+    /// description = "My service"  # default: package description
+    /// install-dir = "/opt/app"  # default: /opt/<bin>
+    /// user = "app"  # default: root
+    /// args = ["--port", "8080"]
+    /// environment = { RUST_LOG = "info" }
+    /// restart = "always"  # default: on-failure
+    /// health-check-url = "http://localhost:8080/health"
+    /// hooks = { BeforeInstall = "deploy/create-user.sh" }
+    /// ```
+    #[throws]
+    pub(crate) fn from_package(package: &Package, bin: &str) -> Self {
+        let metadata = match package.metadata.get("aws-build") {
+            Some(value) => {
+                if !value.is_object() {
+                    throw!(anyhow!(
+                        "package.metadata.aws-build of {} must be a table",
+                        package.name
+                    ));
+                }
+                Metadata::deserialize(value).with_context(|| {
+                    format!(
+                        "invalid package.metadata.aws-build of {}",
+                        package.name
+                    )
+                })?
+            }
+            None => Metadata::default(),
+        };
+
+        let mut hooks = BTreeMap::new();
+        for (event, scripts) in metadata.hooks {
+            if !EVENTS.contains(&event.as_str()) {
+                throw!(anyhow!(
+                    "unknown lifecycle event {}, expected one of {}",
+                    event,
+                    EVENTS.join(", ")
+                ));
+            }
+            hooks.insert(
+                event,
+                scripts.into_vec().into_iter().map(PathBuf::from).collect(),
+            );
+        }
+
+        let install_dir = metadata
+            .install_dir
+            .unwrap_or_else(|| format!("/opt/{}", bin));
+        if !install_dir.starts_with('/') {
+            throw!(anyhow!("install-dir must be absolute"));
+        }
+
+        DeployConfig {
+            description: metadata
+                .description
+                .or_else(|| {
+                    package
+                        .description
+                        .as_deref()
+                        .and_then(|description| description.lines().next())
+                        .map(Into::into)
+                })
+                .unwrap_or_else(|| bin.into()),
+            install_dir: install_dir.trim_end_matches('/').into(),
+            user: metadata.user,
+            args: metadata.args.map(StrList::into_vec).unwrap_or_default(),
+            environment: metadata.environment,
+            restart: metadata.restart.unwrap_or_else(|| "on-failure".into()),
+            health_check_url: metadata.health_check_url,
+            hooks,
+        }
+    }
+}
+
+/// Quote a value for a systemd unit setting if needed.
+fn quote_systemd(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg.contains(|c: char| c.is_whitespace() || "\"'\\%;".contains(c))
+    {
+        return arg.into();
+    }
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{}\"", escaped)
+}
+
+/// Quote an argument for a systemd `ExecStart` line, where `$` also
+/// needs escaping since it starts a variable.
+fn quote_exec_arg(arg: &str) -> String {
+    quote_systemd(&arg.replace('$', "$$"))
+}
+
+/// Create the systemd unit file for `bin`.
+fn unit_file(config: &DeployConfig, bin: &str) -> String {
+    let mut exec = format!("{}/{}", config.install_dir, bin);
+    for arg in &config.args {
+        exec.push(' ');
+        exec.push_str(&quote_exec_arg(arg));
+    }
+
+    let mut unit = String::new();
+    // Writing to a string can't fail
+    let mut line = |text: &str| writeln!(unit, "{}", text).unwrap();
+    line("[Unit]");
+    line(&format!("Description={}", config.description));
+    line("Wants=network-online.target");
+    line("After=network-online.target");
+    line("");
+    line("[Service]");
+    line(&format!("ExecStart={}", exec));
+    line(&format!("Restart={}", config.restart));
+    if let Some(user) = &config.user {
+        line(&format!("User={}", user));
+    }
+    for (key, value) in &config.environment {
+        line(&format!(
+            "Environment={}",
+            quote_systemd(&format!("{}={}", key, value))
+        ));
+    }
+    line("");
+    line("[Install]");
+    line("WantedBy=multi-user.target");
+    unit
+}
+
+/// Create the generated hook scripts, keyed by lifecycle event.
+fn generated_hooks(
+    config: &DeployConfig,
+    bin: &str,
+) -> BTreeMap<&'static str, String> {
+    let service = format!("{}.service", bin);
+    let script = |body: String| format!("#!/bin/sh\nset -eu\n{}", body);
+
+    let mut hooks = BTreeMap::new();
+    // The service doesn't exist on the first deployment
+    hooks.insert(
+        "ApplicationStop",
+        script(format!("systemctl stop {} || true\n", service)),
+    );
+    hooks.insert(
+        "AfterInstall",
+        script(format!(
+            "chmod 755 {}/{}\nsystemctl daemon-reload\n",
+            config.install_dir, bin
+        )),
+    );
+    hooks.insert(
+        "ApplicationStart",
+        script(format!(
+            "systemctl enable {service}\nsystemctl restart {service}\n",
+            service = service
+        )),
+    );
+    let mut validate = format!("systemctl is-active --quiet {}\n", service);
+    if let Some(url) = &config.health_check_url {
+        // Give the service up to a minute to start responding
+        write!(
+            validate,
+            "for _ in $(seq 30); do\n    \
+             if curl --fail --silent --output /dev/null '{}'; then\n        \
+             exit 0\n    fi\n    sleep 2\ndone\n\
+             echo 'health check failed' >&2\nexit 1\n",
+            url.replace('\'', "'\\''")
+        )
+        .unwrap();
+    }
+    hooks.insert("ValidateService", script(validate));
+    hooks
+}
+
+/// Files in the bundle other than the binary, in order: path in the
+/// bundle, contents, and whether the file is executable.
+#[throws]
+fn bundle_files(
+    config: &DeployConfig,
+    bin: &str,
+    package_dir: &Path,
+) -> Vec<(String, Vec<u8>, bool)> {
+    let service = format!("{}.service", bin);
+    let mut files =
+        vec![(service.clone(), unit_file(config, bin).into(), false)];
+
+    let generated = generated_hooks(config, bin);
+    let mut appspec = format!(
+        "version: 0.0\nos: linux\nfiles:\n  \
+         - source: bin/{bin}\n    destination: {dir}\n  \
+         - source: {service}\n    destination: /etc/systemd/system\n\
+         hooks:\n",
+        bin = bin,
+        dir = config.install_dir,
+        service = service
+    );
+    for event in EVENTS {
+        let mut scripts = Vec::new();
+        if let Some(contents) = generated.get(event) {
+            scripts.push((format!("hooks/{}.sh", event), contents.clone()));
+        }
+        for (index, path) in
+            config.hooks.get(event).into_iter().flatten().enumerate()
+        {
+            let contents = fs::read_to_string(package_dir.join(path))?;
+            scripts.push((format!("hooks/{}-{}.sh", event, index), contents));
+        }
+        if scripts.is_empty() {
+            continue;
+        }
+
+        writeln!(appspec, "  {}:", event).unwrap();
+        for (path, contents) in scripts {
+            writeln!(
+                appspec,
+                "    - location: {}\n      timeout: 300\n      runas: root",
+                path
+            )
+            .unwrap();
+            files.push((path, contents.into(), true));
+        }
+    }
+    files.insert(0, ("appspec.yml".into(), appspec.into(), false));
+    files
+}
+
+/// Write a CodeDeploy bundle for the binary `bin` to `path`. Hook
+/// scripts from the config are read relative to `package_dir`.
+#[throws]
+pub(crate) fn write_bundle(
+    path: &Path,
+    config: &DeployConfig,
+    bin: &str,
+    bin_contents: &[u8],
+    package_dir: &Path,
+) {
+    let files = bundle_files(config, bin, package_dir)?;
+
+    let mut zip = ZipWriter::new(fs::File::create(path)?);
+    let options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file(format!("bin/{}", bin), options.unix_permissions(0o755))?;
+    zip.write_all(bin_contents)?;
+    for (name, contents, executable) in files {
+        let permissions = if executable { 0o755 } else { 0o644 };
+        zip.start_file(name, options.unix_permissions(permissions))?;
+        zip.write_all(&contents)?;
+    }
+    zip.finish()?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn package(metadata: Value) -> Package {
+        serde_json::from_value(serde_json::json!({
+            "name": "app",
+            "version": "1.0.0",
+            "id": "app 1.0.0",
+            "dependencies": [],
+            "targets": [],
+            "features": {},
+            "manifest_path": "/src/app/Cargo.toml",
+            "description": "My app\n\nMore about my app.",
+            "metadata": metadata,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_package() {
+        let config =
+            DeployConfig::from_package(&package(Value::Null), "app").unwrap();
+        assert_eq!(config.description, "My app");
+        assert_eq!(config.install_dir, "/opt/app");
+        assert_eq!(config.restart, "on-failure");
+
+        let config = DeployConfig::from_package(
+            &package(serde_json::json!({"aws-build": {
+                "install-dir": "/usr/local/bin/",
+                "user": "app",
+                "args": ["--name", "my app"],
+                "environment": {"RUST_LOG": "info"},
+                "hooks": {"BeforeInstall": "deploy/setup.sh"},
+            }})),
+            "app",
+        )
+        .unwrap();
+        assert_eq!(config.install_dir, "/usr/local/bin");
+        assert_eq!(config.user.as_deref(), Some("app"));
+        assert_eq!(config.args, ["--name", "my app"]);
+        assert_eq!(
+            config.hooks["BeforeInstall"],
+            [Path::new("deploy/setup.sh")]
+        );
+
+        for metadata in [
+            serde_json::json!({"aws-build": {"unknown": 1}}),
+            serde_json::json!({"aws-build": {"hooks": {"Start": "a.sh"}}}),
+            serde_json::json!({"aws-build": {"install-dir": "opt"}}),
+            serde_json::json!({"aws-build": {"args": [1]}}),
+            serde_json::json!({"aws-build": {"environment": {"A": 1}}}),
+            serde_json::json!({"aws-build": []}),
+        ] {
+            assert!(
+                DeployConfig::from_package(&package(metadata.clone()), "app")
+                    .is_err(),
+                "{}",
+                metadata
+            );
+        }
+    }
+
+    #[test]
+    fn test_unit_file() {
+        let config = DeployConfig {
+            description: "My app".into(),
+            install_dir: "/opt/app".into(),
+            user: Some("app".into()),
+            args: vec!["--name".into(), "my \"app\"".into(), "$HOME".into()],
+            environment: [
+                ("RUST_LOG".into(), "info".into()),
+                ("PRICE".into(), "$5".into()),
+            ]
+            .into(),
+            restart: "always".into(),
+            ..Default::default()
+        };
+        let unit = unit_file(&config, "app");
+        for expected in [
+            "Description=My app\n",
+            "ExecStart=/opt/app/app --name \"my \\\"app\\\"\" $$HOME\n",
+            "Restart=always\nUser=app\n",
+            "Environment=PRICE=$5\nEnvironment=RUST_LOG=info\n",
+            "WantedBy=multi-user.target\n",
+        ] {
+            assert!(unit.contains(expected), "{}", unit);
+        }
+    }
+
+    #[test]
+    fn test_bundle_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("deploy")).unwrap();
+        fs::write(dir.path().join("deploy/setup.sh"), "#!/bin/sh\n").unwrap();
+        let config = DeployConfig {
+            install_dir: "/opt/app".into(),
+            health_check_url: Some("http://localhost/health".into()),
+            hooks: [("BeforeInstall".into(), vec!["deploy/setup.sh".into()])]
+                .into(),
+            ..Default::default()
+        };
+
+        let files = bundle_files(&config, "app", dir.path()).unwrap();
+        let names: Vec<_> =
+            files.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "appspec.yml",
+                "app.service",
+                "hooks/ApplicationStop.sh",
+                "hooks/BeforeInstall-0.sh",
+                "hooks/AfterInstall.sh",
+                "hooks/ApplicationStart.sh",
+                "hooks/ValidateService.sh",
+            ]
+        );
+        let appspec = String::from_utf8(files[0].1.clone()).unwrap();
+        assert!(appspec
+            .contains("  - source: bin/app\n    destination: /opt/app\n"));
+        assert!(appspec.contains(
+            "  BeforeInstall:\n    - location: hooks/BeforeInstall-0.sh\n"
+        ));
+        let validate = String::from_utf8(files[6].1.clone()).unwrap();
+        assert!(validate.contains("curl --fail"));
+    }
+}
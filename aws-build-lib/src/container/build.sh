@@ -8,4 +8,100 @@ export RUSTUP_HOME="/rustup"
 # Source cargo environment
 . "${CARGO_HOME}/env"
 
-cargo build --locked --release --target-dir "${TARGET_DIR}" --bin "${BIN_TARGET}"
+# Modification time records when a build last ran in the container
+LAST_BUILD="/tmp/aws-build-last-build"
+
+if [ "${CARGO_COMMAND}" = "idle" ]; then
+    # Keep the container running so that builds can be run in it
+    # with exec. If IDLE_TIMEOUT is set, exit once no build has run
+    # for that many seconds.
+    if [ -z "${IDLE_TIMEOUT:-}" ]; then
+        exec sleep infinity
+    fi
+    touch "${LAST_BUILD}"
+    while true; do
+        sleep 5
+        if ls /tmp/aws-build-running.* > /dev/null 2>&1; then
+            touch "${LAST_BUILD}"
+        fi
+        idle=$(( $(date +%s) - $(stat -c %Y "${LAST_BUILD}") ))
+        if [ "${idle}" -ge "${IDLE_TIMEOUT}" ]; then
+            exit 0
+        fi
+    done
+fi
+
+if [ "${CARGO_COMMAND}" = "rpm" ]; then
+    exec rpmbuild -bb --define "_topdir ${TARGET_DIR}/rpm" \
+         "${TARGET_DIR}/rpm/SPECS/package.spec"
+fi
+
+# Mark the build as running so that an idle container isn't stopped
+# part way through it
+RUNNING="/tmp/aws-build-running.$$"
+touch "${RUNNING}"
+trap 'rm -f "${RUNNING}"; touch "${LAST_BUILD}"' EXIT
+
+# Pass each cargo config file mounted from the host to cargo. The
+# paths are generated by aws-build and never contain whitespace.
+set --
+for config in ${CARGO_CONFIG_FILES:-}; do
+    set -- "$@" --config "${config}"
+done
+
+# Stripping is done here rather than on the host so that the tools
+# always match the target. If objcopy isn't installed, fall back to
+# cargo's strip setting, which needs Rust 1.59 or later.
+STRIP_WITH_OBJCOPY=""
+if command -v objcopy > /dev/null 2>&1; then
+    STRIP_WITH_OBJCOPY="${STRIP:-}"
+elif [ -n "${SPLIT_DEBUG_INFO:-}" ]; then
+    echo "error: splitting debug info requires objcopy in the build image," \
+         "install it with '--package binutils'" >&2
+    exit 1
+elif [ -n "${STRIP:-}" ]; then
+    rust_minor=$(rustc --version | cut -d' ' -f2 | cut -d. -f2)
+    if [ "${rust_minor}" -lt 59 ]; then
+        echo "error: stripping requires either objcopy in the build image" \
+             "or Rust 1.59 or later, install objcopy with" \
+             "'--package binutils'" >&2
+        exit 1
+    fi
+    echo "objcopy is not installed, stripping with cargo instead" >&2
+    export CARGO_PROFILE_RELEASE_STRIP=symbols
+fi
+
+if [ -n "${PRE_BUILD_HOOK:-}" ]; then
+    "${PRE_BUILD_HOOK}"
+fi
+
+if [ "${CARGO_COMMAND}" = "test" ]; then
+    exec cargo "$@" test --locked --release --target-dir "${TARGET_DIR}"
+fi
+
+cargo "$@" build --locked --release --target-dir "${TARGET_DIR}" --bin "${BIN_TARGET}"
+
+# Copy the binary rather than stripping it in place, so that cargo's
+# output keeps its symbols for the size report and isn't already
+# stripped when cargo skips relinking it
+mkdir -p "${TARGET_DIR}/output"
+BIN_PATH="${TARGET_DIR}/output/${BIN_TARGET}"
+cp "${TARGET_DIR}/release/${BIN_TARGET}" "${BIN_PATH}"
+rm -f "${BIN_PATH}.debug"
+if [ -n "${SPLIT_DEBUG_INFO:-}" ]; then
+    objcopy --only-keep-debug "${BIN_PATH}" "${BIN_PATH}.debug"
+    objcopy --strip-debug "${BIN_PATH}"
+fi
+if [ -n "${STRIP_WITH_OBJCOPY}" ]; then
+    objcopy --strip-all "${BIN_PATH}"
+fi
+if [ -n "${SPLIT_DEBUG_INFO:-}" ]; then
+    # The link is added here, before aws-build names the output, so
+    # that the name's hash covers the binary as shipped. It records
+    # the file name "${BIN_TARGET}.debug" and the file's checksum.
+    objcopy --add-gnu-debuglink="${BIN_PATH}.debug" "${BIN_PATH}"
+fi
+
+if [ -n "${POST_BUILD_HOOK:-}" ]; then
+    BIN_PATH="${BIN_PATH}" "${POST_BUILD_HOOK}"
+fi
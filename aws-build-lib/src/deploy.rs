@@ -0,0 +1,263 @@
+//! Update the code of a Lambda function.
+
+use crate::publish::aws_command;
+use crate::set_up_command;
+use anyhow::{anyhow, Context, Error};
+use docker_command::command_run::Command;
+use fehler::{throw, throws};
+use log::info;
+use serde_json::Value;
+use std::path::PathBuf;
+
+/// Where Lambda reads the function's new code from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LambdaCode {
+    /// Zip file on the host, sent directly in the request. Lambda
+    /// limits these to 50 MB.
+    ZipFile(PathBuf),
+
+    /// Zip file already uploaded to S3, e.g. by
+    /// [`S3Publisher`](crate::S3Publisher).
+    S3 {
+        /// Bucket containing the zip file.
+        bucket: String,
+
+        /// Key of the zip file.
+        key: String,
+    },
+}
+
+/// Options for updating a Lambda function's code.
+///
+/// The update is done with the `aws` CLI, which must be installed
+/// and configured with credentials (e.g. with `aws configure` or the
+/// usual `AWS_*` environment variables). The function must already
+/// exist.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LambdaDeployer {
+    /// Name or ARN of the function.
+    pub function: String,
+
+    /// Publish a new version of the function after updating it.
+    pub publish_version: bool,
+
+    /// Alias to point at the new version, created if it doesn't exist.
+    /// Setting this publishes a version even if
+    /// [`LambdaDeployer::publish_version`] is false.
+    pub alias: Option<String>,
+
+    /// URL of a Lambda-compatible API to use instead of AWS, e.g.
+    /// `http://localhost:4566` for LocalStack.
+    pub endpoint_url: Option<String>,
+
+    /// The `aws` CLI program to run, normally `aws` to find it in
+    /// `PATH`.
+    pub aws_program: PathBuf,
+}
+
+/// Check whether a failed command failed because the requested
+/// resource doesn't exist.
+fn is_resource_not_found(stderr: &str) -> bool {
+    stderr.contains("ResourceNotFoundException")
+}
+
+impl LambdaDeployer {
+    fn command(&self, args: &[&str]) -> Command {
+        let mut cmd =
+            aws_command(&self.aws_program, self.endpoint_url.as_deref());
+        cmd.add_args(args);
+        cmd.add_arg_pair("--function-name", &self.function);
+        cmd
+    }
+
+    fn update_code_command(&self, code: &LambdaCode) -> Command {
+        let mut cmd = self.command(&["lambda", "update-function-code"]);
+        match code {
+            LambdaCode::ZipFile(path) => {
+                let mut arg = std::ffi::OsString::from("fileb://");
+                arg.push(path);
+                cmd.add_arg_pair("--zip-file", arg);
+            }
+            LambdaCode::S3 { bucket, key } => {
+                cmd.add_arg_pair("--s3-bucket", bucket);
+                cmd.add_arg_pair("--s3-key", key);
+            }
+        }
+        cmd
+    }
+
+    /// Run `cmd` and parse its JSON output.
+    #[throws]
+    fn run_json(&self, mut cmd: Command) -> Value {
+        cmd.add_arg_pair("--output", "json");
+        cmd.capture = true;
+        set_up_command(&mut cmd);
+        // Keep warnings on stderr out of the JSON
+        cmd.combine_output = false;
+        let output = cmd.run()?;
+        serde_json::from_slice(&output.stdout)
+            .context("failed to parse aws output")?
+    }
+
+    /// Wait until the function's last update has finished.
+    #[throws]
+    fn wait_until_updated(&self) {
+        info!("waiting for {} to finish updating", self.function);
+        let mut cmd = self.command(&["lambda", "wait", "function-updated"]);
+        set_up_command(&mut cmd);
+        cmd.run()?;
+    }
+
+    /// Point the alias at `version`, creating the alias if needed.
+    #[throws]
+    fn move_alias(&self, alias: &str, version: &str) {
+        let mut cmd = self.command(&["lambda", "update-alias"]);
+        cmd.add_arg_pair("--name", alias);
+        cmd.add_arg_pair("--function-version", version);
+        cmd.add_arg_pair("--output", "json");
+        cmd.capture = true;
+        cmd.check = false;
+        cmd.log_command = false;
+        let output = cmd.run()?;
+        if output.status.success() {
+            return;
+        }
+        let stderr = output.stderr_string_lossy();
+        if !is_resource_not_found(&stderr) {
+            throw!(anyhow!("failed to update alias {}: {}", alias, stderr));
+        }
+
+        let mut cmd = self.command(&["lambda", "create-alias"]);
+        cmd.add_arg_pair("--name", alias);
+        cmd.add_arg_pair("--function-version", version);
+        self.run_json(cmd)?;
+    }
+
+    /// Update the function's code and wait for the update to finish.
+    /// Then, if enabled, publish a version and move the alias to it.
+    ///
+    /// Returns the published version, if any.
+    #[throws]
+    pub fn deploy(&self, code: &LambdaCode) -> Option<String> {
+        info!("updating the code of {}", self.function);
+        self.run_json(self.update_code_command(code))?;
+        self.wait_until_updated()?;
+
+        if !self.publish_version && self.alias.is_none() {
+            return None;
+        }
+        let published =
+            self.run_json(self.command(&["lambda", "publish-version"]))?;
+        let version = published["Version"]
+            .as_str()
+            .ok_or_else(|| anyhow!("publish-version did not return a version"))?
+            .to_string();
+        info!("published version {} of {}", version, self.function);
+
+        if let Some(alias) = &self.alias {
+            self.move_alias(alias, &version)?;
+            info!("moved alias {} to version {}", alias, version);
+        }
+        Some(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publish::tests::FakeAws;
+
+    #[test]
+    fn test_commands() {
+        let deployer = LambdaDeployer {
+            function: "my-func".into(),
+            publish_version: false,
+            alias: None,
+            endpoint_url: Some("http://localhost:4566".into()),
+            aws_program: "aws".into(),
+        };
+        assert_eq!(
+            deployer
+                .update_code_command(&LambdaCode::ZipFile(
+                    "/out/lambda-a.zip".into()
+                ))
+                .command_line_lossy(),
+            "aws --endpoint-url http://localhost:4566 lambda \
+             update-function-code --function-name my-func \
+             --zip-file fileb:///out/lambda-a.zip"
+        );
+        assert_eq!(
+            deployer
+                .update_code_command(&LambdaCode::S3 {
+                    bucket: "bucket".into(),
+                    key: "builds/lambda-a.zip".into(),
+                })
+                .command_line_lossy(),
+            "aws --endpoint-url http://localhost:4566 lambda \
+             update-function-code --function-name my-func \
+             --s3-bucket bucket --s3-key builds/lambda-a.zip"
+        );
+    }
+
+    #[test]
+    fn test_deploy() {
+        let aws = FakeAws::new(
+            "case \"$*\" in\n\
+             *publish-version*) echo '{\"Version\": \"3\"}';;\n\
+             *update-alias*) echo 'ResourceNotFoundException' >&2; exit 254;;\n\
+             *) echo '{}';;\n\
+             esac\n",
+        );
+        let deployer = LambdaDeployer {
+            function: "my-func".into(),
+            publish_version: false,
+            alias: Some("live".into()),
+            endpoint_url: None,
+            aws_program: aws.program(),
+        };
+        let code = LambdaCode::S3 {
+            bucket: "bucket".into(),
+            key: "lambda-a.zip".into(),
+        };
+
+        let version = deployer.deploy(&code).unwrap();
+        assert_eq!(version.as_deref(), Some("3"));
+        assert_eq!(
+            aws.log(),
+            [
+                "lambda update-function-code --function-name my-func \
+                 --s3-bucket bucket --s3-key lambda-a.zip --output json",
+                "lambda wait function-updated --function-name my-func",
+                "lambda publish-version --function-name my-func \
+                 --output json",
+                "lambda update-alias --function-name my-func --name live \
+                 --function-version 3 --output json",
+                "lambda create-alias --function-name my-func --name live \
+                 --function-version 3 --output json",
+            ]
+        );
+
+        // Without an alias or publishing, only the code is updated
+        let aws = FakeAws::new("echo '{}'\n");
+        let deployer = LambdaDeployer {
+            alias: None,
+            aws_program: aws.program(),
+            ..deployer
+        };
+        let version = deployer.deploy(&code).unwrap();
+        assert_eq!(version, None);
+        assert_eq!(aws.log().len(), 2, "{:?}", aws.log());
+    }
+
+    #[test]
+    fn test_is_resource_not_found() {
+        assert!(is_resource_not_found(
+            "An error occurred (ResourceNotFoundException) when calling \
+             the UpdateAlias operation: Alias not found"
+        ));
+        assert!(!is_resource_not_found(
+            "An error occurred (AccessDeniedException) when calling the \
+             UpdateAlias operation"
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+//! Run a Lambda zip file locally.
+
+use crate::runtime_emulator::{self, InvocationResult, RuntimeEmulator};
+use anyhow::{anyhow, Error};
+use docker_command::command_run::Command;
+use docker_command::{Launcher, Volume};
+use fehler::{throw, throws};
+use fs_err as fs;
+use log::info;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use zip::ZipArchive;
+
+/// Image that the function runs in.
+// https://github.com/lambci/docker-lambda#documentation
+static LAMBDA_RUNTIME_IMAGE: &str = "docker.io/lambci/lambda:provided.al2";
+
+/// Output returned from [`Invoker::run`] on success.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvokerOutput {
+    /// Response or error sent by the function.
+    pub result: InvocationResult,
+
+    /// Combined stdout and stderr of the function.
+    pub logs: String,
+
+    /// Time from the function receiving the event to the function
+    /// sending a response.
+    pub duration: Duration,
+}
+
+/// Options for invoking a Lambda function locally.
+///
+/// The zip file is unpacked into a `provided.al2` container, and an
+/// event is sent to it through an emulated Lambda runtime API running
+/// in this process. The container uses the host network so that it
+/// can reach the emulator.
+#[must_use]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Invoker {
+    /// Container launcher.
+    pub launcher: Launcher,
+
+    /// Path of the Lambda zip file, e.g. `target/latest-lambda`.
+    pub zip_path: PathBuf,
+
+    /// Event to send to the function, normally JSON.
+    pub event: Vec<u8>,
+
+    /// Extra environment variables to set for the function.
+    pub env: Vec<(String, String)>,
+
+    /// How long to wait for the function to respond.
+    pub timeout: Duration,
+}
+
+impl Invoker {
+    /// Invoke the function once and wait for the result.
+    #[throws]
+    pub fn run(&self) -> InvokerOutput {
+        // Unpack the zip file and make it readable by the user in the
+        // container.
+        let task_dir = TempDir::new()?;
+        let zip_file = fs::File::open(&self.zip_path)?;
+        ZipArchive::new(zip_file)?.extract(task_dir.path())?;
+        fs::set_permissions(
+            task_dir.path(),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+
+        let emulator = RuntimeEmulator::start(self.timeout)?;
+        let request_id = emulator.push_event(self.event.clone());
+
+        // Use a unique name so that the container can be stopped once
+        // the response has been received.
+        let container_name = format!("aws-build-invoke-{}", process::id());
+        let cmd = self.run_command(&container_name, &emulator, &task_dir)?;
+        info!("{}", cmd.command_line_lossy());
+
+        // Send all output from the container to a temporary file so
+        // that stdout and stderr stay in order.
+        let mut logs_file = tempfile::tempfile()?;
+        let mut child = process::Command::from(&cmd)
+            .stdout(logs_file.try_clone()?)
+            .stderr(logs_file.try_clone()?)
+            .spawn()?;
+
+        let start = Instant::now();
+        let poll_interval = Duration::from_millis(100);
+        let result = loop {
+            if let Some(invocation) =
+                emulator.wait_for_invocation(&request_id, poll_interval)
+            {
+                break Ok(invocation);
+            }
+            if let Some(payload) = emulator.init_error() {
+                break Err(anyhow!(
+                    "function failed to initialize: {}",
+                    String::from_utf8_lossy(&payload.body)
+                ));
+            }
+            if let Some(status) = child.try_wait()? {
+                break Err(anyhow!(
+                    "function exited before responding: {}",
+                    status
+                ));
+            }
+            if start.elapsed() > self.timeout {
+                break Err(anyhow!(
+                    "function timed out after {:?}",
+                    self.timeout
+                ));
+            }
+        };
+
+        // Stop the container, ignoring errors since it may have
+        // already exited.
+        let mut kill_cmd = self.launcher.base_command().clone();
+        kill_cmd.add_arg_pair("kill", &container_name);
+        kill_cmd.log_command = false;
+        kill_cmd.capture = true;
+        kill_cmd.check = false;
+        kill_cmd.run()?;
+        child.wait()?;
+
+        let mut logs = Vec::new();
+        logs_file.seek(SeekFrom::Start(0))?;
+        logs_file.read_to_end(&mut logs)?;
+        let logs = String::from_utf8_lossy(&logs).into_owned();
+
+        let invocation = match result {
+            Ok(invocation) => invocation,
+            Err(err) => throw!(err.context(format!("logs:\n{}", logs))),
+        };
+        InvokerOutput {
+            result: invocation.result,
+            logs,
+            duration: invocation.duration,
+        }
+    }
+
+    /// Create the command that runs the function in a container.
+    ///
+    /// This is created by hand rather than with [`Launcher::run`]
+    /// because the image's entrypoint needs to be replaced.
+    #[throws]
+    fn run_command(
+        &self,
+        container_name: &str,
+        emulator: &RuntimeEmulator,
+        task_dir: &TempDir,
+    ) -> Command {
+        let mut cmd = self.launcher.base_command().clone();
+        cmd.add_args(["run", "--rm", "--network", "host"]);
+        cmd.add_arg_pair("--name", container_name);
+        cmd.add_arg_pair("--entrypoint", "/var/task/bootstrap");
+        cmd.add_arg_pair(
+            "--volume",
+            Volume {
+                src: task_dir.path().into(),
+                dst: "/var/task".into(),
+                read_write: false,
+                options: Vec::new(),
+            }
+            .arg(),
+        );
+
+        let function_name = runtime_emulator::FUNCTION_NAME;
+        let mut env = vec![
+            (
+                "AWS_LAMBDA_RUNTIME_API".into(),
+                emulator.addr()?.to_string(),
+            ),
+            ("AWS_LAMBDA_FUNCTION_NAME".into(), function_name.into()),
+            ("AWS_LAMBDA_FUNCTION_VERSION".into(), "$LATEST".into()),
+            ("AWS_LAMBDA_FUNCTION_MEMORY_SIZE".into(), "128".into()),
+            (
+                "AWS_LAMBDA_LOG_GROUP_NAME".into(),
+                format!("/aws/lambda/{}", function_name),
+            ),
+            ("AWS_LAMBDA_LOG_STREAM_NAME".into(), "local".into()),
+            ("AWS_REGION".into(), "us-east-1".into()),
+            ("LAMBDA_TASK_ROOT".into(), "/var/task".into()),
+            ("_HANDLER".into(), "bootstrap".into()),
+        ];
+        env.extend(self.env.iter().cloned());
+        for (key, value) in env {
+            cmd.add_arg_pair("--env", format!("{}={}", key, value));
+        }
+
+        cmd.add_arg(LAMBDA_RUNTIME_IMAGE);
+        cmd
+    }
+}
@@ -3,25 +3,59 @@
 //! Build a Rust project in a container for deployment to either
 //! Amazon Linux 2 or AWS Lambda.
 
+mod code_root;
+mod codedeploy;
+mod deploy;
+mod invoke;
+mod naming;
+mod prune;
+mod publish;
+mod rpm;
+pub mod runtime_emulator;
+mod size;
+mod smoke;
+mod template;
+mod warm;
+mod watch;
+mod zip_files;
+
+pub use deploy::{LambdaCode, LambdaDeployer};
 pub use docker_command;
+pub use invoke::{Invoker, InvokerOutput};
+pub use naming::NameTemplate;
+pub use prune::Retention;
+pub use publish::{PublishedObject, S3Publisher};
+pub use rpm::RpmOptions;
+pub use size::{CrateSize, SizeReport};
+pub use smoke::SmokeRunner;
+pub use template::TemplateKind;
+pub use warm::stop_reused_containers;
+pub use zip_files::{ExtraZipFile, ZipCompression};
 
 use anyhow::{anyhow, Context, Error};
-use cargo_metadata::MetadataCommand;
+use cargo_metadata::{MetadataCommand, Package};
 use docker_command::command_run::{Command, LogTo};
 use docker_command::{BuildOpt, Launcher, RunOpt, UserAndGroup, Volume};
 use fehler::{throw, throws};
 use fs_err as fs;
-use log::{error, info};
-use sha2::Digest;
+use log::{error, info, warn};
+use naming::{NameValues, Placeholder};
+use std::ffi::OsString;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
-use time::{Date, OffsetDateTime};
-use zip::ZipWriter;
+use time::OffsetDateTime;
+use zip::{ZipArchive, ZipWriter};
 
 /// Default rust version to install.
 pub static DEFAULT_RUST_VERSION: &str = "stable";
 
+/// Stock Amazon Linux 2 image.
+// https://hub.docker.com/_/amazonlinux
+static AMAZON_LINUX_2_IMAGE: &str = "docker.io/amazonlinux:2";
+
 /// Create directory if it doesn't already exist.
 #[throws]
 fn ensure_dir_exists(path: &Path) {
@@ -47,11 +81,42 @@ fn get_package_binaries(path: &Path) -> Vec<String> {
     names
 }
 
+/// Paths of the cargo workspace that contains a project.
+struct WorkspacePaths {
+    root: PathBuf,
+    target_dir: PathBuf,
+}
+
+#[throws]
+fn get_workspace_paths(project_path: &Path) -> WorkspacePaths {
+    let metadata = MetadataCommand::new()
+        .current_dir(project_path)
+        .no_deps()
+        .exec()?;
+    WorkspacePaths {
+        root: metadata.workspace_root.into(),
+        target_dir: metadata.target_directory.into(),
+    }
+}
+
+/// Line in the Dockerfile after which custom snippets are inserted.
+static DOCKERFILE_SNIPPET_MARKER: &str =
+    "# Custom Dockerfile snippets are inserted here\n";
+
 #[throws]
-fn write_container_files() -> TempDir {
+fn write_container_files(dockerfile_snippets: &[String]) -> TempDir {
     let tmp_dir = TempDir::new()?;
 
-    let dockerfile = include_str!("container/Dockerfile");
+    let mut dockerfile = include_str!("container/Dockerfile").to_string();
+    let mut snippets = String::new();
+    for snippet in dockerfile_snippets {
+        snippets.push_str(snippet.trim_end());
+        snippets.push('\n');
+    }
+    dockerfile = dockerfile.replace(
+        DOCKERFILE_SNIPPET_MARKER,
+        &format!("{}{}", DOCKERFILE_SNIPPET_MARKER, snippets),
+    );
     fs::write(tmp_dir.path().join("Dockerfile"), dockerfile)?;
 
     let build_script = include_str!("container/build.sh");
@@ -60,47 +125,62 @@ fn write_container_files() -> TempDir {
     tmp_dir
 }
 
+/// Get the absolute path of a hook script. This is necessary for
+/// when it's passed as a Docker volume arg.
+#[throws]
+fn canonicalize_hook(path: &Path) -> PathBuf {
+    fs::canonicalize(path)
+        .with_context(|| format!("invalid hook path {}", path.display()))?
+}
+
+/// Create the parent directory of the symlink path and get the
+/// absolute symlink path. Only the parent is canonicalized since the
+/// symlink itself may not exist yet.
+#[throws]
+fn prepare_symlink_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid symlink path {}", path.display()))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+    fs::canonicalize(parent)?.join(file_name)
+}
+
 fn set_up_command(cmd: &mut Command) {
     cmd.log_to = LogTo::Log;
     cmd.combine_output = true;
     cmd.log_output_on_error = true;
 }
 
-/// Create a unique output file name.
-///
-/// The file name is intended to be identifiable, sortable by time,
-/// unique, and reasonably short. To make this it includes:
-/// - build-mode prefix (al2 or lambda)
-/// - executable name
-/// - year, month, and day
-/// - first 16 digits of the sha256 hex hash
-fn make_unique_name(
-    mode: BuildMode,
-    name: &str,
-    contents: &[u8],
-    when: Date,
-) -> String {
-    let hash = sha2::Sha256::digest(contents);
-    format!(
-        "{}-{}-{}{:02}{:02}-{:.16x}",
-        mode.name(),
-        name,
-        when.year(),
-        u8::from(when.month()),
-        when.day(),
-        // The hash is truncated to 16 characters so that the file
-        // name isn't unnecessarily long
-        hash
-    )
+/// Get the version of the package that contains the `bin` target.
+#[throws]
+fn get_bin_package(path: &Path, bin: &str) -> Package {
+    let metadata = MetadataCommand::new().current_dir(path).no_deps().exec()?;
+    metadata
+        .packages
+        .into_iter()
+        .find(|package| {
+            package.targets.iter().any(|target| {
+                target.name == bin && target.kind.contains(&"bin".to_string())
+            })
+        })
+        .ok_or_else(|| anyhow!("package containing {} not found", bin))?
 }
 
-/// Run the strip command to remove symbols and decrease the size.
+/// Get the abbreviated hash of the HEAD commit of the git repo that
+/// contains `path`.
 #[throws]
-fn strip(path: &Path) {
-    let mut cmd = Command::new("strip");
-    cmd.add_arg(path);
-    set_up_command(&mut cmd);
-    cmd.run()?;
+fn get_git_sha(path: &Path) -> String {
+    let mut cmd =
+        Command::with_args("git", ["rev-parse", "--short=12", "HEAD"]);
+    cmd.set_dir(path);
+    cmd.capture = true;
+    cmd.log_command = false;
+    let output = cmd.run().context("failed to get git sha")?;
+    output.stdout_string_lossy().trim().to_string()
 }
 
 /// Recursively set the owner of `dir` using the `podman unshare`
@@ -111,7 +191,7 @@ fn strip(path: &Path) {
 fn set_podman_permissions(user: &UserAndGroup, dir: &Path) {
     Command::with_args(
         "podman",
-        &["unshare", "chown", "--recursive", &user.arg()],
+        ["unshare", "chown", "--recursive", &user.arg()],
     )
     .add_arg(dir)
     .run()?;
@@ -152,22 +232,83 @@ impl<'a> Drop for ResetPodmanPermissions<'a> {
     }
 }
 
+/// Validated inputs shared by all the commands that run in the build
+/// container.
+struct Prepared {
+    code_root: PathBuf,
+    code_mounts: Vec<PathBuf>,
+    project_path: PathBuf,
+    output_dir: PathBuf,
+    symlink_path: Option<PathBuf>,
+    cargo_config: Vec<PathBuf>,
+    env: Vec<(String, String)>,
+    pre_build_hook: Option<PathBuf>,
+    post_build_hook: Option<PathBuf>,
+    image_tag: String,
+}
+
+/// Cargo command that the build script runs in the container.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CargoCommand {
+    Build,
+    Test,
+
+    /// Don't run cargo, just keep the container running so that
+    /// builds can be run in it with `exec`.
+    Idle,
+
+    /// Don't run cargo, just build an RPM from the files staged in
+    /// the output directory.
+    Rpm,
+}
+
+impl CargoCommand {
+    fn name(&self) -> &'static str {
+        match self {
+            CargoCommand::Build => "build",
+            CargoCommand::Test => "test",
+            CargoCommand::Idle => "idle",
+            CargoCommand::Rpm => "rpm",
+        }
+    }
+}
+
 struct Container<'a> {
     mode: BuildMode,
-    bin: &'a String,
+    command: CargoCommand,
+
+    /// Binary target to build. Not used when running tests.
+    bin: Option<&'a str>,
     launcher: &'a Launcher,
     output_dir: &'a Path,
     image_tag: &'a str,
     relabel: Option<Relabel>,
+    cargo_config: &'a [PathBuf],
+    env: &'a [(String, String)],
+    pre_build_hook: Option<&'a Path>,
+    post_build_hook: Option<&'a Path>,
 
     /// The root of the code that gets mounted in the container. All the
     /// source must live beneath this directory.
     code_root: &'a Path,
+
+    /// Paths beneath the code root to mount in the container.
+    code_mounts: &'a [PathBuf],
+
+    /// Strip the binary after building it. This is done in the
+    /// container so that the tools match the target.
+    strip: bool,
+
+    /// Move the binary's debug info to `<bin>.debug` after building
+    /// it, and link to that file from the binary.
+    split_debug_info: bool,
 }
 
 impl<'a> Container<'a> {
+    /// Get the options for running the container. This also creates
+    /// the cache directories if they don't already exist.
     #[throws]
-    fn run(&self) -> PathBuf {
+    fn run_opt(&self) -> RunOpt {
         let mode_name = self.mode.name();
 
         // Create two cache directories to speed up rebuilds. These are
@@ -180,6 +321,127 @@ impl<'a> Container<'a> {
         let git_dir = self.output_dir.join(format!("{}-cargo-git", mode_name));
         ensure_dir_exists(&git_dir)?;
 
+        let mount_options = match self.relabel {
+            Some(Relabel::Shared) => vec!["z".to_string()],
+            Some(Relabel::Unshared) => vec!["Z".to_string()],
+            None => vec![],
+        };
+
+        let mut volumes = vec![
+            // Mount two cargo directories to make rebuilds faster
+            Volume {
+                src: registry_dir,
+                dst: Path::new("/cargo/registry").into(),
+                read_write: true,
+                options: mount_options.clone(),
+            },
+            Volume {
+                src: git_dir,
+                dst: Path::new("/cargo/git").into(),
+                read_write: true,
+                options: mount_options.clone(),
+            },
+            // Mount the output target directory
+            Volume {
+                src: self.output_dir.into(),
+                dst: Path::new("/target").into(),
+                read_write: true,
+                options: mount_options.clone(),
+            },
+        ];
+
+        // Mount the code. Normally this is the whole code root, but
+        // with minimal mounts it's a set of paths beneath it.
+        for src in self.code_mounts {
+            let relative = src
+                .strip_prefix(self.code_root)
+                .context("mount path must be within the code root")?;
+            volumes.push(Volume {
+                src: src.clone(),
+                dst: Path::new("/code").join(relative),
+                read_write: false,
+                options: mount_options.clone(),
+            });
+        }
+
+        // Mount each cargo config file from the host. The container
+        // paths are passed to the build script so that it can add a
+        // `--config` argument for each one.
+        let mut config_paths = Vec::new();
+        for (index, src) in self.cargo_config.iter().enumerate() {
+            let dst =
+                Path::new("/cargo/host-config").join(format!("{}.toml", index));
+            config_paths.push(dst.display().to_string());
+            volumes.push(Volume {
+                src: src.clone(),
+                dst,
+                read_write: false,
+                options: mount_options.clone(),
+            });
+        }
+
+        // Mount the hook scripts, if any
+        let mut hook_env = Vec::new();
+        let hooks = [
+            ("PRE_BUILD_HOOK", "/hooks/pre-build", self.pre_build_hook),
+            ("POST_BUILD_HOOK", "/hooks/post-build", self.post_build_hook),
+        ];
+        for (var, dst, src) in hooks {
+            if let Some(src) = src {
+                volumes.push(Volume {
+                    src: src.into(),
+                    dst: dst.into(),
+                    read_write: false,
+                    options: mount_options.clone(),
+                });
+                hook_env.push((var.into(), dst.into()));
+            }
+        }
+
+        // User-provided variables come first so that they can't
+        // override the variables the build script relies on.
+        let mut env: Vec<(OsString, OsString)> = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.into(), value.into()))
+            .collect();
+        env.extend([
+            (
+                "TARGET_DIR".into(),
+                Path::new("/target").join(mode_name).into(),
+            ),
+            ("CARGO_COMMAND".into(), self.command.name().into()),
+            ("CARGO_CONFIG_FILES".into(), config_paths.join(" ").into()),
+        ]);
+        if let Some(bin) = self.bin {
+            env.push(("BIN_TARGET".into(), bin.into()));
+        }
+        if self.strip {
+            env.push(("STRIP".into(), "1".into()));
+        }
+        if self.split_debug_info {
+            env.push(("SPLIT_DEBUG_INFO".into(), "1".into()));
+        }
+        env.extend(hook_env);
+
+        RunOpt {
+            remove: true,
+            env,
+            init: true,
+            user: Some(UserAndGroup::current()),
+            volumes,
+            image: self.image_tag.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Call `f` with the output directory's permissions set up such
+    /// that the container can write to it.
+    #[throws]
+    fn with_output_permissions<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, Error>,
+    ) -> T {
         let mut reset_podman_permissions = None;
         if self.launcher.is_podman() {
             // Recursively set the output directory's permissions such
@@ -195,57 +457,7 @@ impl<'a> Container<'a> {
             ));
         }
 
-        let mount_options = match self.relabel {
-            Some(Relabel::Shared) => vec!["z".to_string()],
-            Some(Relabel::Unshared) => vec!["Z".to_string()],
-            None => vec![],
-        };
-
-        let mut cmd = self.launcher.run(RunOpt {
-            remove: true,
-            env: vec![
-                (
-                    "TARGET_DIR".into(),
-                    Path::new("/target").join(mode_name).into(),
-                ),
-                ("BIN_TARGET".into(), self.bin.into()),
-            ],
-            init: true,
-            user: Some(UserAndGroup::current()),
-            volumes: vec![
-                // Mount the code root
-                Volume {
-                    src: self.code_root.into(),
-                    dst: Path::new("/code").into(),
-                    read_write: false,
-                    options: mount_options.clone(),
-                },
-                // Mount two cargo directories to make rebuilds faster
-                Volume {
-                    src: registry_dir,
-                    dst: Path::new("/cargo/registry").into(),
-                    read_write: true,
-                    options: mount_options.clone(),
-                },
-                Volume {
-                    src: git_dir,
-                    dst: Path::new("/cargo/git").into(),
-                    read_write: true,
-                    options: mount_options.clone(),
-                },
-                // Mount the output target directory
-                Volume {
-                    src: self.output_dir.into(),
-                    dst: Path::new("/target").into(),
-                    read_write: true,
-                    options: mount_options,
-                },
-            ],
-            image: self.image_tag.into(),
-            ..Default::default()
-        });
-        set_up_command(&mut cmd);
-        cmd.run()?;
+        let output = f()?;
 
         if let Some(mut resetter) = reset_podman_permissions {
             // Recursively set the output directory's permissions back
@@ -253,11 +465,19 @@ impl<'a> Container<'a> {
             resetter.reset_permissions()?;
         }
 
-        // Return the path of the binary that was built
-        self.output_dir
-            .join(mode_name)
-            .join("release")
-            .join(self.bin)
+        output
+    }
+
+    /// Run the container to completion.
+    #[throws]
+    fn run(&self) {
+        let opt = self.run_opt()?;
+        self.with_output_permissions(|| {
+            let mut cmd = self.launcher.run(opt);
+            set_up_command(&mut cmd);
+            cmd.run()?;
+            Ok(())
+        })?;
     }
 }
 
@@ -275,7 +495,9 @@ pub enum BuildMode {
 }
 
 impl BuildMode {
-    fn name(&self) -> &'static str {
+    /// Short name of the mode as used on the command line and in
+    /// output file names, either "al2" or "lambda".
+    pub fn name(&self) -> &'static str {
         match self {
             BuildMode::AmazonLinux2 => "al2",
             BuildMode::Lambda => "lambda",
@@ -313,8 +535,62 @@ pub struct BuilderOutput {
     /// Path of the generated file.
     pub real: PathBuf,
 
-    /// Path of the `latest-*` symlink.
-    pub symlink: PathBuf,
+    /// Path of the `latest-*` symlink, unless disabled by
+    /// [`Builder::symlink`].
+    pub symlink: Option<PathBuf>,
+
+    /// Path of the separate debug info file, if
+    /// [`Builder::split_debug_info`] is set.
+    pub debug: Option<PathBuf>,
+
+    /// Sizes of the output and the binary in it, or `None` if the
+    /// binary built by cargo couldn't be read as an ELF binary.
+    pub size: Option<SizeReport>,
+
+    /// Path of the RPM, if [`Builder::rpm`] is set.
+    pub rpm: Option<PathBuf>,
+
+    /// Path of the CodeDeploy bundle, if [`Builder::codedeploy`] is
+    /// set.
+    pub codedeploy: Option<PathBuf>,
+
+    /// Path of the template snippet, if [`Builder::template`] is set.
+    pub template: Option<PathBuf>,
+}
+
+/// Where to create the symlink that points to the latest output file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Symlink {
+    /// `latest-al2` or `latest-lambda` in the target directory. See
+    /// [`default_symlink_path`].
+    Default,
+
+    /// Create the symlink at a custom path.
+    Custom(PathBuf),
+
+    /// Don't create a symlink.
+    Disabled,
+}
+
+/// Get the cargo target directory of the project's workspace. This
+/// takes `$CARGO_TARGET_DIR` and cargo config into account.
+#[throws]
+pub fn target_dir(project_path: &Path) -> PathBuf {
+    get_workspace_paths(project_path)?.target_dir
+}
+
+/// Get the default path of the symlink that points to the latest
+/// output file for `mode`, e.g. `target/latest-lambda`.
+#[throws]
+pub fn default_symlink_path(project_path: &Path, mode: BuildMode) -> PathBuf {
+    target_dir(project_path)?.join(format!("latest-{}", mode.name()))
+}
+
+/// Get the default directory that outputs and build caches are
+/// written to, `aws-build` in the target directory.
+#[throws]
+pub fn default_output_dir(project_path: &Path) -> PathBuf {
+    target_dir(project_path)?.join("aws-build")
 }
 
 /// Options for running the build.
@@ -332,15 +608,33 @@ pub struct Builder {
     /// only has one binary target.
     pub bin: Option<String>,
 
-    /// Strip the binary.
+    /// Strip the binary. This is done in the container with its
+    /// `objcopy`, falling back to cargo's `strip` profile setting if
+    /// the image doesn't have one.
     pub strip: bool,
 
+    /// Move the binary's debug info to a separate file next to the
+    /// output, with the same name plus a `.debug` extension (replacing
+    /// `.zip` in Lambda mode).
+    ///
+    /// The binary gets a `.gnu_debuglink` section with the file's
+    /// checksum and the name `<bin>.debug`, since the link is added
+    /// before the output is named so that the name's `{hash}` covers
+    /// the final binary. Debuggers find the file when it's installed
+    /// under that name, e.g. next to the binary.
+    ///
+    /// This is done in the container and requires `objcopy` (from
+    /// binutils) in the build image.
+    pub split_debug_info: bool,
+
     /// Container launcher.
     pub launcher: Launcher,
 
     /// The root of the code that gets mounted in the container. All the
-    /// source must live beneath this directory.
-    pub code_root: PathBuf,
+    /// source must live beneath this directory, including path
+    /// dependencies. Defaults to the smallest directory that contains
+    /// the project's workspace and all of its path dependencies.
+    pub code_root: Option<PathBuf>,
 
     /// The project path is the path of the crate to build. It must be
     /// somewhere within the `code_root` directory (or the same path).
@@ -356,6 +650,126 @@ pub struct Builder {
     ///
     /// [break your system]: https://docs.docker.com/storage/bind-mounts/#configure-the-selinux-label
     pub relabel: Option<Relabel>,
+
+    /// Cargo config files to apply to the build, e.g.
+    /// `~/.cargo/config.toml` or a config file above the code
+    /// root. These would otherwise be invisible inside the
+    /// container. Each file is mounted read-only into the container's
+    /// `CARGO_HOME` and passed to cargo with `--config`, in the order
+    /// given. This requires Rust 1.63 or newer.
+    pub cargo_config: Vec<PathBuf>,
+
+    /// Extra environment variables to set in the build container,
+    /// e.g. inputs to build scripts such as `PROTOC`.
+    pub env: Vec<(String, String)>,
+
+    /// Flags to pass to rustc, e.g. `-C lto=fat`. These are appended
+    /// to `RUSTFLAGS` if it is also set in `env`. Note that cargo
+    /// ignores any `rustflags` from config files when `RUSTFLAGS` is
+    /// set.
+    pub rustflags: Option<String>,
+
+    /// CPU to generate code for, e.g. `neoverse-n1`. This is passed
+    /// to rustc as `-C target-cpu` via `RUSTFLAGS`.
+    pub target_cpu: Option<String>,
+
+    /// Base image to use instead of the default for the build mode,
+    /// e.g. a hardened Amazon Linux 2 image mirrored to a local
+    /// registry. The image must be based on Amazon Linux 2.
+    pub base_image: Option<String>,
+
+    /// Dockerfile instructions to add to the build image, e.g. to add
+    /// a yum repo or install a tool from a tarball. The snippets are
+    /// inserted in order after Rust is installed and before the
+    /// `packages` are installed.
+    pub dockerfile_snippets: Vec<String>,
+
+    /// Executable script to run in the container before `cargo
+    /// build`, e.g. to generate code. It runs in the project
+    /// directory. The code root is mounted read-only, so any output
+    /// should be written beneath `$TARGET_DIR`.
+    pub pre_build_hook: Option<PathBuf>,
+
+    /// Executable script to run in the container after a successful
+    /// `cargo build`, e.g. to run tests or to collect extra assets
+    /// into the output directory (`$TARGET_DIR`). The path of the
+    /// binary, after any stripping, is passed in `$BIN_PATH`.
+    pub post_build_hook: Option<PathBuf>,
+
+    /// Keep the build container running after the build and reuse it
+    /// for later builds with the same image, code root, and options,
+    /// rather than starting a new container each time. The container
+    /// stops itself once no build has run in it for the given idle
+    /// timeout. See also [`stop_reused_containers`].
+    pub reuse_container: Option<Duration>,
+
+    /// Old outputs to delete after a successful build. By default all
+    /// outputs are kept.
+    pub retention: Retention,
+
+    /// Template for the names of output files.
+    pub name_template: NameTemplate,
+
+    /// Directory to write outputs and build caches to. Defaults to
+    /// [`default_output_dir`].
+    pub output_dir: Option<PathBuf>,
+
+    /// Where to create the symlink to the latest output file.
+    pub symlink: Symlink,
+
+    /// Instead of mounting the whole code root, only mount the
+    /// directories of the workspace members and path dependencies,
+    /// plus the workspace's `Cargo.toml`, `Cargo.lock`, and `.cargo`
+    /// directory, and any `directory` sources (e.g. vendored
+    /// dependencies) in the code root that its cargo config names.
+    /// Other files in the code root are not visible to the build.
+    ///
+    /// This includes files that a package reads from outside its own
+    /// directory, such as `include_str!("../README.md")`, a
+    /// `license-file` or `readme` in a parent directory, inputs of a
+    /// build script, and `.cargo/config.toml` files above the
+    /// workspace. Builds that need them should mount the whole code
+    /// root instead.
+    pub minimal_mounts: bool,
+
+    /// Fail the build if the output file is larger than this many
+    /// bytes. In Lambda mode this is the size of the zip file, which
+    /// affects cold start time. Outputs over the budget are deleted.
+    pub size_budget: Option<u64>,
+
+    /// How to compress the Lambda zip file.
+    pub zip_compression: ZipCompression,
+
+    /// Deflate compression level of the Lambda zip file, from 0
+    /// (fastest) to 9 (smallest). Defaults to the zip crate's default
+    /// level. Can't be set with [`ZipCompression::Stored`].
+    pub zip_compression_level: Option<u32>,
+
+    /// Files and directories to add to the Lambda zip file alongside
+    /// the `bootstrap` executable. Executable files keep their
+    /// execute permission; everything else is readable by all.
+    pub extra_zip_files: Vec<ExtraZipFile>,
+
+    /// Also package the binary as an RPM, written next to the output
+    /// with the standard `name-version-release.arch.rpm` file name.
+    /// Amazon Linux 2 mode only. `rpm-build` is added to the packages
+    /// installed in the build image.
+    pub rpm: Option<RpmOptions>,
+
+    /// Also package the binary as a CodeDeploy bundle: a zip file
+    /// containing the binary, a systemd unit, an `appspec.yml`, and
+    /// lifecycle hook scripts that install and restart the service.
+    /// They are configured by `[package.metadata.aws-build]` in the
+    /// binary's package; see the readme for the settings. The bundle
+    /// has the same name as the output plus `.zip`, in a `codedeploy`
+    /// directory next to it. Amazon Linux 2 mode only.
+    pub codedeploy: bool,
+
+    /// Also write a SAM or CloudFormation resource snippet for the
+    /// function, referencing the zip file. It has the same name as
+    /// the zip with a `.yaml` extension, in a `templates` directory
+    /// next to it. Lambda mode only.
+    pub template: Option<TemplateKind>,
 }
 
 impl Builder {
@@ -370,30 +784,30 @@ impl Builder {
     /// The paths of the files are returned.
     #[throws]
     pub fn run(&self) -> BuilderOutput {
-        // Canonicalize the input paths. This is necessary for when it's
-        // passed as a Docker volume arg.
-        let code_root = fs::canonicalize(&self.code_root)?;
-        let project_path = fs::canonicalize(&self.project_path)?;
-        let relative_project_path = project_path
-            .strip_prefix(&code_root)
-            .context("project path must be within the code root")?;
-
-        // Ensure that the target directory exists
-        let target_dir = project_path.join("target");
-        ensure_dir_exists(&target_dir)?;
+        let prepared = self.prepare()?;
+        let bin = self.bin_name(&prepared)?;
 
-        let output_dir = target_dir.join("aws-build");
-        ensure_dir_exists(&output_dir)?;
+        // Build the project in a container
+        if let Some(idle_timeout) = self.reuse_container {
+            let container = self.container(&prepared, CargoCommand::Idle, None);
+            warm::build_in_daemon(&container, idle_timeout, &bin)
+                .context("container build failed")?;
+        } else {
+            self.container(&prepared, CargoCommand::Build, Some(&bin))
+                .run()
+                .context("container run failed")?;
+        }
 
-        let image_tag = self
-            .build_container(relative_project_path)
-            .context("container build failed")?;
+        self.package_output(&prepared, &bin)?
+    }
 
+    /// Get the name of the binary target to build.
+    #[throws]
+    fn bin_name(&self, prepared: &Prepared) -> String {
         // Get the binary target names
-        let binaries = get_package_binaries(&project_path)?;
+        let binaries = get_package_binaries(&prepared.project_path)?;
 
-        // Get the name of the binary target to build
-        let bin: String = if let Some(bin) = &self.bin {
+        if let Some(bin) = &self.bin {
             bin.clone()
         } else if binaries.len() == 1 {
             binaries[0].clone()
@@ -401,42 +815,87 @@ impl Builder {
             throw!(anyhow!(
                 "must specify bin target when package has more than one"
             ));
-        };
-
-        // Build the project in a container
-        let container = Container {
-            mode: self.mode,
-            launcher: &self.launcher,
-            output_dir: &output_dir,
-            image_tag: &image_tag,
-            bin: &bin,
-            relabel: self.relabel,
-            code_root: &code_root,
-        };
-        let bin_path = container.run().context("container run failed")?;
-
-        // Optionally strip symbols
-        if self.strip {
-            strip(&bin_path)?;
         }
+    }
+
+    /// Give the binary built by the container a unique name, package
+    /// it for the build mode, and update the `latest-*` symlink.
+    #[throws]
+    fn package_output(&self, prepared: &Prepared, bin: &str) -> BuilderOutput {
+        let output_dir = &prepared.output_dir;
+        // The container copies the binary built by cargo to the output
+        // directory, stripping it and moving its debug info to a
+        // separate file if requested
+        let cargo_bin_path =
+            output_dir.join(self.mode.name()).join("release").join(bin);
+        let bin_path =
+            output_dir.join(self.mode.name()).join("output").join(bin);
+        let debug_tmp_path = bin_path.with_file_name(format!("{}.debug", bin));
 
         let bin_contents = fs::read(&bin_path)?;
-        let base_unique_name = make_unique_name(
-            self.mode,
-            &bin,
-            &bin_contents,
-            OffsetDateTime::now_utc().date(),
-        );
+        let version = if self.name_template.uses(Placeholder::Version) {
+            get_bin_package(&prepared.project_path, bin)?
+                .version
+                .to_string()
+        } else {
+            String::new()
+        };
+        let git_sha = if self.name_template.uses(Placeholder::GitSha) {
+            Some(get_git_sha(&prepared.code_root)?)
+        } else {
+            None
+        };
+        let base_unique_name = self.name_template.render(&NameValues {
+            mode: self.mode,
+            bin,
+            version: &version,
+            git_sha: git_sha.as_deref(),
+            profile: "release",
+            contents: &bin_contents,
+            when: OffsetDateTime::now_utc(),
+        })?;
+
+        // Give the debug info file the same name as the output. The
+        // container already linked to it from the binary.
+        let debug_path = if self.split_debug_info {
+            let debug_path = output_dir
+                .join(self.mode.name())
+                .join(format!("{}.debug", base_unique_name));
+            fs::rename(&debug_tmp_path, &debug_path)?;
+            info!("writing {}", debug_path.display());
+            Some(debug_path)
+        } else {
+            None
+        };
 
+        let mut zip_size = None;
+        let mut rpm_path = None;
+        let mut codedeploy_path = None;
+        let mut template_path = None;
         let out_path = match self.mode {
             BuildMode::AmazonLinux2 => {
                 // Give the binary a unique name so that multiple
                 // versions can be uploaded to S3 without overwriting
                 // each other.
                 let out_path =
-                    output_dir.join(self.mode.name()).join(base_unique_name);
-                fs::copy(bin_path, &out_path)?;
+                    output_dir.join(self.mode.name()).join(&base_unique_name);
+                fs::copy(&bin_path, &out_path)?;
                 info!("writing {}", out_path.display());
+
+                if self.codedeploy {
+                    codedeploy_path = Some(self.build_codedeploy_bundle(
+                        prepared,
+                        bin,
+                        &bin_contents,
+                        &base_unique_name,
+                    )?);
+                }
+
+                if let Some(options) = &self.rpm {
+                    rpm_path = Some(
+                        self.build_rpm(prepared, options, bin, &bin_path)?,
+                    );
+                }
                 out_path
             }
             BuildMode::Lambda => {
@@ -447,59 +906,446 @@ impl Builder {
                 let zip_path =
                     output_dir.join(self.mode.name()).join(&zip_name);
 
-                // Create the zip file containing just a bootstrap
-                // file (the executable)
+                // Create the zip file containing a bootstrap file (the
+                // executable) and any extra files
+                let extra_entries =
+                    zip_files::collect_entries(&self.extra_zip_files)?;
                 info!("writing {}", zip_path.display());
                 let file = fs::File::create(&zip_path)?;
                 let mut zip = ZipWriter::new(file);
-                let options = zip::write::FileOptions::default()
-                    .unix_permissions(0o755)
-                    .compression_method(zip::CompressionMethod::Deflated);
-                zip.start_file("bootstrap", options)?;
+                let options = self
+                    .zip_compression
+                    .file_options(self.zip_compression_level)?
+                    .unix_permissions(0o755);
+                zip.start_file(zip_files::BOOTSTRAP, options)?;
                 zip.write_all(&bin_contents)?;
 
+                for (name, entry) in extra_entries {
+                    zip.start_file(
+                        name,
+                        options.unix_permissions(entry.permissions),
+                    )?;
+                    zip.write_all(&fs::read(&entry.src)?)?;
+                }
+
                 zip.finish()?;
 
+                let mut archive = ZipArchive::new(fs::File::open(&zip_path)?)?;
+                zip_size = Some(
+                    archive.by_name(zip_files::BOOTSTRAP)?.compressed_size(),
+                );
+
+                if let Some(kind) = self.template {
+                    let template_dir = output_dir
+                        .join(self.mode.name())
+                        .join(template::TEMPLATE_DIR);
+                    ensure_dir_exists(&template_dir)?;
+                    let path = template_dir
+                        .join(Path::new(&zip_name).with_extension("yaml"));
+                    info!("writing {}", path.display());
+                    fs::write(&path, template::render(kind, bin, &zip_path)?)?;
+                    template_path = Some(path);
+                }
+
                 zip_path
             }
         };
 
-        // Create a symlink pointing to the output file. Either
-        // "target/latest-al2" or "target/latest-lambda"
-        let symlink_path =
-            target_dir.join(format!("latest-{}", self.mode.name()));
-        // Remove the symlink if it already exists, but ignore an
-        // error in case it doesn't exist.
-        let _ = fs::remove_file(&symlink_path);
-        std::os::unix::fs::symlink(&out_path, &symlink_path)?;
-        info!("symlink: {}", symlink_path.display());
+        let total = fs::metadata(&out_path)?.len();
+        let size = match size::read_elf(&fs::read(&cargo_bin_path)?) {
+            Ok(elf_info) => {
+                let unstripped = fs::metadata(&cargo_bin_path)?.len();
+                let size = SizeReport {
+                    total,
+                    binary: bin_contents.len() as u64,
+                    unstripped,
+                    stripped: unstripped.saturating_sub(elf_info.strippable),
+                    zip: zip_size,
+                    crates: size::crate_sizes(&elf_info.functions),
+                };
+                info!("{}", size);
+                Some(size)
+            }
+            Err(err) => {
+                warn!("skipping the size report: {:#}", err);
+                None
+            }
+        };
+
+        // The report is logged first so that a build over the budget
+        // still shows what takes up the space
+        if let Some(budget) = self.size_budget {
+            if total > budget {
+                // Don't leave an output that failed the budget where
+                // it could be mistaken for a good one
+                for path in [
+                    Some(&out_path),
+                    debug_path.as_ref(),
+                    rpm_path.as_ref(),
+                    codedeploy_path.as_ref(),
+                    template_path.as_ref(),
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    fs::remove_file(path)?;
+                }
+                throw!(anyhow!(
+                    "{} is {} bytes, which exceeds the size budget of {} bytes",
+                    out_path.display(),
+                    total,
+                    budget
+                ));
+            }
+        }
+
+        // Create a symlink pointing to the output file. By default
+        // either "target/latest-al2" or "target/latest-lambda"
+        if let Some(symlink_path) = &prepared.symlink_path {
+            // Remove the symlink if it already exists, but ignore an
+            // error in case it doesn't exist.
+            let _ = fs::remove_file(symlink_path);
+            std::os::unix::fs::symlink(&out_path, symlink_path)?;
+            info!("symlink: {}", symlink_path.display());
+        }
+
+        self.retention
+            .prune(self.mode, &self.name_template, output_dir, Some(&out_path))
+            .context("failed to prune old outputs")?;
 
         BuilderOutput {
             real: out_path,
-            symlink: symlink_path,
+            symlink: prepared.symlink_path.clone(),
+            debug: debug_path,
+            size,
+            rpm: rpm_path,
+            codedeploy: codedeploy_path,
+            template: template_path,
+        }
+    }
+
+    /// Write a CodeDeploy bundle containing `bin_contents`. Returns
+    /// the bundle's path.
+    #[throws]
+    fn build_codedeploy_bundle(
+        &self,
+        prepared: &Prepared,
+        bin: &str,
+        bin_contents: &[u8],
+        name: &str,
+    ) -> PathBuf {
+        let package = get_bin_package(&prepared.project_path, bin)?;
+        let config = codedeploy::DeployConfig::from_package(&package, bin)
+            .context("invalid package.metadata.aws-build")?;
+        let package_dir: &Path = package
+            .manifest_path
+            .parent()
+            .ok_or_else(|| anyhow!("invalid manifest path"))?
+            .as_ref();
+
+        let bundle_dir = prepared
+            .output_dir
+            .join(self.mode.name())
+            .join(codedeploy::BUNDLE_DIR);
+        ensure_dir_exists(&bundle_dir)?;
+        let bundle_path = bundle_dir.join(format!("{}.zip", name));
+        info!("writing {}", bundle_path.display());
+        codedeploy::write_bundle(
+            &bundle_path,
+            &config,
+            bin,
+            bin_contents,
+            package_dir,
+        )?;
+        bundle_path
+    }
+
+    /// Package the binary at `bin_path` as an RPM, built in the
+    /// container. Returns the RPM's path in the output directory.
+    #[throws]
+    fn build_rpm(
+        &self,
+        prepared: &Prepared,
+        options: &RpmOptions,
+        bin: &str,
+        bin_path: &Path,
+    ) -> PathBuf {
+        let mode_dir = prepared.output_dir.join(self.mode.name());
+        let rpm_dir = mode_dir.join(rpm::RPM_DIR);
+        let package = get_bin_package(&prepared.project_path, bin)?;
+        rpm::stage(&rpm_dir, options, &package, bin, bin_path)?;
+
+        self.container(prepared, CargoCommand::Rpm, Some(bin))
+            .run()
+            .context("rpmbuild failed")?;
+
+        let built = rpm::find_built_rpm(&rpm_dir)?;
+        let rpm_path = mode_dir.join(
+            built
+                .file_name()
+                .ok_or_else(|| anyhow!("invalid RPM path"))?,
+        );
+        fs::rename(&built, &rpm_path)?;
+        info!("writing {}", rpm_path.display());
+        rpm_path
+    }
+
+    /// Run the project's tests in a container.
+    ///
+    /// This uses the same container image and mounts as [`run`], so
+    /// the tests run against the same environment that the binary is
+    /// built for. The tests are built in release mode so that the
+    /// build cache is shared with [`run`].
+    ///
+    /// [`run`]: Self::run
+    #[throws]
+    pub fn test(&self) {
+        let prepared = self.prepare()?;
+        self.container(&prepared, CargoCommand::Test, None)
+            .run()
+            .context("container run failed")?;
+    }
+
+    /// Build the project, then rebuild it whenever a file beneath the
+    /// code root changes. This only returns if setting up the build
+    /// fails.
+    ///
+    /// The image is built once and the container is kept running
+    /// between builds, so each rebuild only pays for `cargo build`.
+    /// A failed build is logged and does not stop watching.
+    ///
+    /// Changes are found by polling: every half second the
+    /// modification time of each file beneath the mounted paths is
+    /// read, which can use noticeable CPU for a large code root.
+    /// Hidden and `target` directories are skipped, apart from cargo
+    /// config files in `.cargo`.
+    ///
+    /// `on_build` is called with the output of each successful
+    /// build. An error returned from it is logged.
+    pub fn watch<F>(&self, mut on_build: F) -> Result<(), Error>
+    where
+        F: FnMut(&BuilderOutput) -> Result<(), Error>,
+    {
+        let prepared = self.prepare()?;
+        let bin = self.bin_name(&prepared)?;
+        let container = self.container(&prepared, CargoCommand::Idle, None);
+        let warm = warm::WarmContainer::start(&container)?;
+
+        // Only watch the paths that are mounted in the container, and
+        // don't let the build's own outputs trigger another build
+        let mut exclude = vec![prepared.output_dir.as_path()];
+        exclude.extend(prepared.symlink_path.as_deref());
+
+        let poll_interval = Duration::from_millis(500);
+        let mut previous = watch::Snapshot::new();
+        loop {
+            let current = watch::snapshot(&prepared.code_mounts, &exclude)?;
+            if current == previous {
+                thread::sleep(poll_interval);
+                continue;
+            }
+
+            // Wait for the changes to settle, since editors often
+            // write several files (or one file several times) at once.
+            thread::sleep(poll_interval);
+            if watch::snapshot(&prepared.code_mounts, &exclude)? != current {
+                continue;
+            }
+            previous = current;
+
+            let output = warm::exec_build(&container, &warm.name, &bin)
+                .context("container build failed")
+                .and_then(|_| self.package_output(&prepared, &bin));
+            match output {
+                Ok(output) => {
+                    if let Err(err) = on_build(&output) {
+                        error!("{:#}", err);
+                    }
+                }
+                Err(err) => error!("{:#}", err),
+            }
+            info!("waiting for changes in {}", prepared.code_root.display());
+        }
+    }
+
+    /// Validate the inputs, create the output directories, and build
+    /// the container image.
+    #[throws]
+    fn prepare(&self) -> Prepared {
+        // Check the zip options before building rather than after
+        self.zip_compression
+            .file_options(self.zip_compression_level)?;
+        if !self.extra_zip_files.is_empty() {
+            if self.mode != BuildMode::Lambda {
+                throw!(anyhow!("extra zip files require Lambda mode"));
+            }
+            zip_files::collect_entries(&self.extra_zip_files)?;
+        }
+        if self.rpm.is_some() && self.mode != BuildMode::AmazonLinux2 {
+            throw!(anyhow!("RPM packaging requires Amazon Linux 2 mode"));
+        }
+        if self.codedeploy && self.mode != BuildMode::AmazonLinux2 {
+            throw!(anyhow!("CodeDeploy bundles require Amazon Linux 2 mode"));
+        }
+        if self.template.is_some() && self.mode != BuildMode::Lambda {
+            throw!(anyhow!("template snippets require Lambda mode"));
+        }
+
+        // Canonicalize the input paths. This is necessary for when it's
+        // passed as a Docker volume arg.
+        let project_path = fs::canonicalize(&self.project_path)?;
+        let workspace = get_workspace_paths(&project_path)?;
+        let code_root =
+            self.code_root.as_ref().map(fs::canonicalize).transpose()?;
+        let path_deps = code_root::find_path_dependencies(&project_path)?;
+        let code_root = code_root::choose_code_root(
+            code_root.as_deref(),
+            &fs::canonicalize(&workspace.root)?,
+            &path_deps,
+        )?;
+        let relative_project_path = project_path
+            .strip_prefix(&code_root)
+            .context("project path must be within the code root")?;
+        let code_mounts = if self.minimal_mounts {
+            code_root::minimal_mounts(&project_path, &path_deps, &code_root)?
+        } else {
+            vec![code_root.clone()]
+        };
+
+        let cargo_config = self
+            .cargo_config
+            .iter()
+            .map(|path| {
+                fs::canonicalize(path).with_context(|| {
+                    format!("invalid cargo config path {}", path.display())
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let pre_build_hook = self
+            .pre_build_hook
+            .as_ref()
+            .map(|path| canonicalize_hook(path))
+            .transpose()?;
+        let post_build_hook = self
+            .post_build_hook
+            .as_ref()
+            .map(|path| canonicalize_hook(path))
+            .transpose()?;
+
+        // Ensure that the output directory exists. It's canonicalized
+        // since it gets passed as a Docker volume arg.
+        let output_dir = self
+            .output_dir
+            .clone()
+            .unwrap_or_else(|| workspace.target_dir.join("aws-build"));
+        fs::create_dir_all(&output_dir)?;
+        let output_dir = fs::canonicalize(&output_dir)?;
+
+        let symlink_path = match &self.symlink {
+            Symlink::Default => Some(
+                workspace
+                    .target_dir
+                    .join(format!("latest-{}", self.mode.name())),
+            ),
+            Symlink::Custom(path) => Some(path.clone()),
+            Symlink::Disabled => None,
+        };
+        let symlink_path = symlink_path
+            .as_deref()
+            .map(prepare_symlink_path)
+            .transpose()?;
+
+        let image_tag = self
+            .build_container(relative_project_path)
+            .context("container build failed")?;
+
+        Prepared {
+            code_root,
+            code_mounts,
+            project_path,
+            output_dir,
+            symlink_path,
+            cargo_config,
+            env: self.container_env(),
+            pre_build_hook,
+            post_build_hook,
+            image_tag,
+        }
+    }
+
+    fn container<'a>(
+        &'a self,
+        prepared: &'a Prepared,
+        command: CargoCommand,
+        bin: Option<&'a str>,
+    ) -> Container<'a> {
+        Container {
+            mode: self.mode,
+            command,
+            bin,
+            launcher: &self.launcher,
+            output_dir: &prepared.output_dir,
+            image_tag: &prepared.image_tag,
+            relabel: self.relabel,
+            cargo_config: &prepared.cargo_config,
+            env: &prepared.env,
+            pre_build_hook: prepared.pre_build_hook.as_deref(),
+            post_build_hook: prepared.post_build_hook.as_deref(),
+            code_root: &prepared.code_root,
+            code_mounts: &prepared.code_mounts,
+            strip: self.strip,
+            split_debug_info: self.split_debug_info,
+        }
+    }
+
+    /// Get the environment variables to set in the build container.
+    ///
+    /// This is `env` with `rustflags` and `target_cpu` merged into
+    /// `RUSTFLAGS`.
+    fn container_env(&self) -> Vec<(String, String)> {
+        let mut rustflags = Vec::new();
+        let mut env = Vec::new();
+        for (key, value) in &self.env {
+            if key == "RUSTFLAGS" {
+                rustflags.push(value.clone());
+            } else {
+                env.push((key.clone(), value.clone()));
+            }
         }
+        if let Some(flags) = &self.rustflags {
+            rustflags.push(flags.clone());
+        }
+        if let Some(cpu) = &self.target_cpu {
+            rustflags.push(format!("-C target-cpu={}", cpu));
+        }
+        if !rustflags.is_empty() {
+            env.push(("RUSTFLAGS".into(), rustflags.join(" ")));
+        }
+        env
     }
 
     /// Build the container image and return its hash.
     #[throws]
     fn build_container(&self, relative_project_path: &Path) -> String {
-        let from = match self.mode {
-            BuildMode::AmazonLinux2 => {
-                // https://hub.docker.com/_/amazonlinux
-                "docker.io/amazonlinux:2"
-            }
+        let default_from = match self.mode {
+            BuildMode::AmazonLinux2 => AMAZON_LINUX_2_IMAGE,
             BuildMode::Lambda => {
                 // https://github.com/lambci/docker-lambda#documentation
                 "docker.io/lambci/lambda:build-provided.al2"
             }
         };
-        let tmp_dir = write_container_files()?;
+        let from = self.base_image.as_deref().unwrap_or(default_from);
+        let mut packages = self.packages.clone();
+        if self.rpm.is_some() {
+            packages.push("rpm-build".into());
+        }
+        let tmp_dir = write_container_files(&self.dockerfile_snippets)?;
         let iid_path = tmp_dir.path().join("iidfile");
         let mut cmd = self.launcher.build(BuildOpt {
             build_args: vec![
                 ("FROM_IMAGE".into(), from.into()),
                 ("RUST_VERSION".into(), self.rust_version.clone()),
-                ("DEV_PKGS".into(), self.packages.join(" ")),
+                ("DEV_PKGS".into(), packages.join(" ")),
                 (
                     "PROJECT_PATH".into(),
                     relative_project_path
@@ -521,19 +1367,77 @@ impl Builder {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use time::Month;
+
+    fn test_builder() -> Builder {
+        Builder {
+            rust_version: DEFAULT_RUST_VERSION.into(),
+            mode: BuildMode::Lambda,
+            bin: None,
+            strip: false,
+            split_debug_info: false,
+            launcher: Launcher::new(Command::new("docker")),
+            code_root: None,
+            project_path: PathBuf::new(),
+            packages: Vec::new(),
+            relabel: None,
+            cargo_config: Vec::new(),
+            env: Vec::new(),
+            rustflags: None,
+            target_cpu: None,
+            base_image: None,
+            dockerfile_snippets: Vec::new(),
+            pre_build_hook: None,
+            post_build_hook: None,
+            reuse_container: None,
+            retention: Retention::default(),
+            name_template: NameTemplate::default(),
+            output_dir: None,
+            symlink: Symlink::Default,
+            minimal_mounts: false,
+            size_budget: None,
+            zip_compression: ZipCompression::Deflated,
+            zip_compression_level: None,
+            extra_zip_files: Vec::new(),
+            rpm: None,
+            codedeploy: false,
+            template: None,
+        }
+    }
 
     #[test]
-    fn test_unique_name() {
-        let when = Date::from_calendar_date(2020, Month::August, 31).unwrap();
+    fn test_container_env() {
+        let mut builder = test_builder();
+        assert!(builder.container_env().is_empty());
+
+        builder.env = vec![
+            ("PROTOC".into(), "/usr/bin/protoc".into()),
+            ("RUSTFLAGS".into(), "-C lto=fat".into()),
+        ];
+        builder.rustflags = Some("-C opt-level=s".into());
+        builder.target_cpu = Some("neoverse-n1".into());
         assert_eq!(
-            make_unique_name(
-                BuildMode::Lambda,
-                "testexecutable",
-                "testcontents".as_bytes(),
-                when
-            ),
-            "lambda-testexecutable-20200831-7097a82a108e78da"
+            builder.container_env(),
+            vec![
+                ("PROTOC".into(), "/usr/bin/protoc".into()),
+                (
+                    "RUSTFLAGS".into(),
+                    "-C lto=fat -C opt-level=s -C target-cpu=neoverse-n1"
+                        .into()
+                ),
+            ]
         );
     }
+
+    #[test]
+    fn test_dockerfile_snippets() {
+        let snippets =
+            vec!["RUN echo one\n".to_string(), "RUN echo two".to_string()];
+        let tmp_dir = write_container_files(&snippets).unwrap();
+        let dockerfile =
+            fs::read_to_string(tmp_dir.path().join("Dockerfile")).unwrap();
+        assert!(dockerfile.contains(&format!(
+            "{}RUN echo one\nRUN echo two\n",
+            DOCKERFILE_SNIPPET_MARKER
+        )));
+    }
 }
@@ -0,0 +1,398 @@
+//! Output file naming.
+
+use crate::BuildMode;
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use sha2::Digest;
+use std::fmt;
+use std::str::FromStr;
+use time::OffsetDateTime;
+
+/// Value that can be substituted into a [`NameTemplate`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Placeholder {
+    Mode,
+    Arch,
+    Bin,
+    Version,
+    GitSha,
+    Hash,
+    Date,
+    Time,
+    Profile,
+}
+
+impl Placeholder {
+    const ALL: [Placeholder; 9] = [
+        Placeholder::Mode,
+        Placeholder::Arch,
+        Placeholder::Bin,
+        Placeholder::Version,
+        Placeholder::GitSha,
+        Placeholder::Hash,
+        Placeholder::Date,
+        Placeholder::Time,
+        Placeholder::Profile,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Placeholder::Mode => "mode",
+            Placeholder::Arch => "arch",
+            Placeholder::Bin => "bin",
+            Placeholder::Version => "version",
+            Placeholder::GitSha => "git_sha",
+            Placeholder::Hash => "hash",
+            Placeholder::Date => "date",
+            Placeholder::Time => "time",
+            Placeholder::Profile => "profile",
+        }
+    }
+
+    /// Check whether `value` could have been substituted for this
+    /// placeholder.
+    fn matches(&self, value: &str) -> bool {
+        let is_digits = |len| {
+            value.len() == len && value.bytes().all(|b| b.is_ascii_digit())
+        };
+        match self {
+            Placeholder::Mode => value == "al2" || value == "lambda",
+            Placeholder::Arch => {
+                value == "x86_64" || value == "arm64" || value == arch_name()
+            }
+            Placeholder::Hash => {
+                value.len() == 16
+                    && value.bytes().all(|b| b.is_ascii_hexdigit())
+            }
+            Placeholder::Date => is_digits(8),
+            Placeholder::Time => is_digits(6),
+            Placeholder::Bin
+            | Placeholder::Version
+            | Placeholder::GitSha
+            | Placeholder::Profile => !value.is_empty(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Template for the names of output files.
+///
+/// Placeholders are written in braces, e.g. `{bin}-{version}`. The
+/// available placeholders are:
+/// - `mode`: build-mode prefix (al2 or lambda)
+/// - `arch`: target architecture (x86_64 or arm64)
+/// - `bin`: executable name
+/// - `version`: version of the package that contains the executable
+/// - `git_sha`: abbreviated hash of the code root's git HEAD commit
+/// - `hash`: first 16 digits of the executable's sha256 hex hash
+/// - `date`: year, month, and day (UTC)
+/// - `time`: hour, minute, and second (UTC)
+/// - `profile`: cargo profile used for the build
+///
+/// In Lambda mode `.zip` is appended to the name.
+///
+/// The template must contain `{hash}` so that a rebuild with
+/// different contents never overwrites a previous output, and pruning
+/// relies on the hash to tell outputs apart from other files in the
+/// output directory.
+///
+/// The default template is `{mode}-{bin}-{date}-{hash}`, which is
+/// intended to be identifiable, sortable by time, unique, and
+/// reasonably short.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NameTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        "{mode}-{bin}-{date}-{hash}".parse().unwrap()
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = Error;
+
+    #[throws]
+    fn from_str(template: &str) -> Self {
+        if template.is_empty() {
+            throw!(anyhow!("naming template is empty"));
+        }
+        if template.contains('/') {
+            throw!(anyhow!("naming template must not contain '/'"));
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            if let Some(after_open) = rest.strip_prefix('{') {
+                let (name, after_close) =
+                    after_open.split_once('}').ok_or_else(|| {
+                        anyhow!("unclosed '{{' in naming template")
+                    })?;
+                let placeholder = Placeholder::ALL
+                    .iter()
+                    .find(|p| p.name() == name)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "unknown placeholder {{{}}} in naming template",
+                            name
+                        )
+                    })?;
+                if let Some(Part::Placeholder(_)) = parts.last() {
+                    // Adjacent placeholders can't be told apart when
+                    // matching existing file names
+                    throw!(anyhow!(
+                        "placeholders in naming template must be separated"
+                    ));
+                }
+                parts.push(Part::Placeholder(*placeholder));
+                rest = after_close;
+            } else {
+                let end = rest.find('{').unwrap_or(rest.len());
+                let literal = &rest[..end];
+                if literal.contains('}') {
+                    throw!(anyhow!("unmatched '}}' in naming template"));
+                }
+                parts.push(Part::Literal(literal.into()));
+                rest = &rest[end..];
+            }
+        }
+
+        if !parts.contains(&Part::Placeholder(Placeholder::Hash)) {
+            throw!(anyhow!(
+                "naming template must contain {{hash}} so that names are unique"
+            ));
+        }
+        if !parts.iter().any(|part| matches!(part, Part::Literal(_))) {
+            throw!(anyhow!(
+                "naming template must contain a literal separator, e.g. '-'"
+            ));
+        }
+
+        NameTemplate {
+            template: template.into(),
+            parts,
+        }
+    }
+}
+
+/// Values substituted into a [`NameTemplate`].
+pub(crate) struct NameValues<'a> {
+    pub(crate) mode: BuildMode,
+    pub(crate) bin: &'a str,
+    pub(crate) version: &'a str,
+    pub(crate) git_sha: Option<&'a str>,
+    pub(crate) profile: &'a str,
+    pub(crate) contents: &'a [u8],
+    pub(crate) when: OffsetDateTime,
+}
+
+/// Get the name of the architecture the build runs on, using the same
+/// names as AWS.
+pub(crate) fn arch_name() -> &'static str {
+    match std::env::consts::ARCH {
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+impl NameTemplate {
+    /// Check whether the template uses `placeholder`.
+    pub(crate) fn uses(&self, placeholder: Placeholder) -> bool {
+        self.parts.contains(&Part::Placeholder(placeholder))
+    }
+
+    /// Create a file name by substituting `values` into the template.
+    #[throws]
+    pub(crate) fn render(&self, values: &NameValues) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => name.push_str(literal),
+                Part::Placeholder(placeholder) => {
+                    name.push_str(&render_placeholder(*placeholder, values)?)
+                }
+            }
+        }
+        name
+    }
+
+    /// Match `name` against the template. On success the value of
+    /// each placeholder is returned.
+    pub(crate) fn match_name<'a>(
+        &self,
+        name: &'a str,
+    ) -> Option<Vec<(Placeholder, &'a str)>> {
+        let mut values = Vec::new();
+        if match_parts(&self.parts, name, &mut values) {
+            Some(values)
+        } else {
+            None
+        }
+    }
+}
+
+#[throws]
+fn render_placeholder(placeholder: Placeholder, values: &NameValues) -> String {
+    let when = values.when;
+    match placeholder {
+        Placeholder::Mode => values.mode.name().into(),
+        Placeholder::Arch => arch_name().into(),
+        Placeholder::Bin => values.bin.into(),
+        Placeholder::Version => values.version.into(),
+        Placeholder::GitSha => values
+            .git_sha
+            .ok_or_else(|| anyhow!("git sha is not available"))?
+            .into(),
+        // The hash is truncated to 16 characters so that the file
+        // name isn't unnecessarily long
+        Placeholder::Hash => {
+            format!("{:.16x}", sha2::Sha256::digest(values.contents))
+        }
+        Placeholder::Date => format!(
+            "{}{:02}{:02}",
+            when.year(),
+            u8::from(when.month()),
+            when.day()
+        ),
+        Placeholder::Time => format!(
+            "{:02}{:02}{:02}",
+            when.hour(),
+            when.minute(),
+            when.second()
+        ),
+        Placeholder::Profile => values.profile.into(),
+    }
+}
+
+/// Recursively match `name` against `parts`, trying the longest
+/// value for each placeholder first.
+fn match_parts<'a>(
+    parts: &[Part],
+    name: &'a str,
+    values: &mut Vec<(Placeholder, &'a str)>,
+) -> bool {
+    let (part, rest) = if let Some(split) = parts.split_first() {
+        split
+    } else {
+        return name.is_empty();
+    };
+    match part {
+        Part::Literal(literal) => name
+            .strip_prefix(literal.as_str())
+            .is_some_and(|name| match_parts(rest, name, values)),
+        Part::Placeholder(placeholder) => {
+            for end in (1..=name.len()).rev() {
+                if !name.is_char_boundary(end) {
+                    continue;
+                }
+                let value = &name[..end];
+                if !placeholder.matches(value) {
+                    continue;
+                }
+                values.push((*placeholder, value));
+                if match_parts(rest, &name[end..], values) {
+                    return true;
+                }
+                values.pop();
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month};
+
+    fn values() -> NameValues<'static> {
+        NameValues {
+            mode: BuildMode::Lambda,
+            bin: "testexecutable",
+            version: "1.2.3",
+            git_sha: Some("0123456789ab"),
+            profile: "release",
+            contents: b"testcontents",
+            when: Date::from_calendar_date(2020, Month::August, 31)
+                .unwrap()
+                .with_hms(12, 34, 56)
+                .unwrap()
+                .assume_utc(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            NameTemplate::default().render(&values()).unwrap(),
+            "lambda-testexecutable-20200831-7097a82a108e78da"
+        );
+
+        let template: NameTemplate =
+            "{bin}_{version}_{git_sha}_{date}T{time}_{profile}_{arch}_{hash}"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            template.render(&values()).unwrap(),
+            format!(
+                "testexecutable_1.2.3_0123456789ab_20200831T123456_release_{}_\
+                 7097a82a108e78da",
+                arch_name()
+            )
+        );
+
+        let template: NameTemplate = "{bin}-{git_sha}-{hash}".parse().unwrap();
+        let mut values = values();
+        values.git_sha = None;
+        assert!(template.render(&values).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        for template in [
+            "",
+            "a/{bin}-{hash}",
+            "{bin-{hash}",
+            "bin}-{hash}",
+            "{unknown}-{hash}",
+            "{bin}{hash}",
+            "{bin}-{version}",
+            "{hash}",
+        ] {
+            assert!(template.parse::<NameTemplate>().is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn test_match_name() {
+        let template = NameTemplate::default();
+        assert_eq!(
+            template.match_name("al2-my-bin-20200831-7097a82a108e78da"),
+            Some(vec![
+                (Placeholder::Mode, "al2"),
+                (Placeholder::Bin, "my-bin"),
+                (Placeholder::Date, "20200831"),
+                (Placeholder::Hash, "7097a82a108e78da"),
+            ])
+        );
+        assert_eq!(template.match_name("al2-cargo-registry"), None);
+        assert_eq!(
+            template.match_name("al2-bin-2020083-7097a82a108e78da"),
+            None
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! Delete old build outputs.
+
+use crate::codedeploy;
+use crate::naming::{NameTemplate, Placeholder};
+use crate::template;
+use crate::BuildMode;
+use anyhow::Error;
+use fehler::throws;
+use fs_err as fs;
+use log::info;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use time::{Date, Month, OffsetDateTime};
+
+/// Which build outputs to keep when pruning.
+///
+/// Outputs are grouped by binary, and an output is kept if either
+/// rule keeps it. If neither rule is set, nothing is deleted.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Retention {
+    /// Keep the newest `keep_last` outputs of each binary.
+    pub keep_last: Option<usize>,
+
+    /// Keep outputs built in the last `keep_days` days, counting
+    /// today as the first day. The date is read from the file name if
+    /// the naming template has a `{date}`, otherwise the file's
+    /// modification time is used.
+    pub keep_days: Option<u32>,
+}
+
+/// Build output whose name matches the naming template.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Artifact {
+    path: PathBuf,
+    bin: String,
+    date: Date,
+    modified: SystemTime,
+}
+
+/// Parse the date of a `{date}` placeholder value.
+fn parse_date(date: &str) -> Option<Date> {
+    let year = date.get(0..4)?.parse().ok()?;
+    let month = Month::try_from(date.get(4..6)?.parse::<u8>().ok()?).ok()?;
+    let day = date.get(6..8)?.parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// Match a file name against `template`. Returns the bin name (empty
+/// if the template has no `{bin}`) and the date (if the template has
+/// a `{date}`), or `None` if the name doesn't match.
+fn parse_artifact_name<'a>(
+    mode: BuildMode,
+    template: &NameTemplate,
+    file_name: &'a str,
+) -> Option<(&'a str, Option<Date>)> {
+    let name = match mode {
+        BuildMode::AmazonLinux2 => file_name,
+        BuildMode::Lambda => file_name.strip_suffix(".zip")?,
+    };
+
+    let mut bin = "";
+    let mut date = None;
+    for (placeholder, value) in template.match_name(name)? {
+        match placeholder {
+            Placeholder::Mode if value != mode.name() => return None,
+            Placeholder::Bin => bin = value,
+            Placeholder::Date => date = Some(parse_date(value)?),
+            _ => {}
+        }
+    }
+    Some((bin, date))
+}
+
+/// Get the paths of the files written alongside the output at `path`,
+/// which are deleted with it. They're named after the output, without
+/// `.zip` in Lambda mode: the debug info file with `.debug` added, the
+/// CodeDeploy bundle and the template snippet.
+fn companion_paths(mode: BuildMode, path: &Path) -> Vec<PathBuf> {
+    let (dir, name) = match (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Vec::new(),
+    };
+    let stem = match mode {
+        BuildMode::AmazonLinux2 => name,
+        BuildMode::Lambda => name.strip_suffix(".zip").unwrap_or(name),
+    };
+    vec![
+        dir.join(format!("{}.debug", stem)),
+        dir.join(codedeploy::BUNDLE_DIR)
+            .join(format!("{}.zip", stem)),
+        dir.join(template::TEMPLATE_DIR)
+            .join(format!("{}.yaml", stem)),
+    ]
+}
+
+impl Retention {
+    /// Delete the outputs in `output_dir` (e.g. `target/aws-build`)
+    /// for `mode` that aren't kept by this policy. Outputs are found by
+    /// matching file names against `template`. `protected` is
+    /// never deleted, even if the policy doesn't keep it; it's
+    /// normally the target of the `latest-*` symlink. Files that
+    /// don't look like build outputs are ignored.
+    ///
+    /// Returns the paths of the deleted files.
+    #[throws]
+    pub fn prune(
+        &self,
+        mode: BuildMode,
+        template: &NameTemplate,
+        output_dir: &Path,
+        protected: Option<&Path>,
+    ) -> Vec<PathBuf> {
+        let dir = output_dir.join(mode.name());
+        let protected = protected.and_then(|path| fs::canonicalize(path).ok());
+
+        let mut artifacts = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let file_name = entry.file_name();
+            // Debug info files are deleted along with their output
+            if Path::new(&file_name).extension() == Some("debug".as_ref()) {
+                continue;
+            }
+            let (bin, date) = if let Some(parsed) = file_name
+                .to_str()
+                .and_then(|name| parse_artifact_name(mode, template, name))
+            {
+                parsed
+            } else {
+                continue;
+            };
+            let modified = metadata.modified()?;
+            artifacts.push(Artifact {
+                path: entry.path(),
+                bin: bin.into(),
+                date: date
+                    .unwrap_or_else(|| OffsetDateTime::from(modified).date()),
+                modified,
+            });
+        }
+
+        let today = OffsetDateTime::now_utc().date();
+        let mut deleted = Vec::new();
+        for path in self.select(artifacts, today) {
+            if fs::canonicalize(&path).ok() == protected {
+                continue;
+            }
+            let companions = companion_paths(mode, &path);
+            for path in [path].into_iter().chain(companions) {
+                if path.exists() {
+                    info!("deleting {}", path.display());
+                    fs::remove_file(&path)?;
+                    deleted.push(path);
+                }
+            }
+        }
+        deleted
+    }
+
+    /// Get the paths of the artifacts that this policy doesn't keep.
+    fn select(&self, artifacts: Vec<Artifact>, today: Date) -> Vec<PathBuf> {
+        if self.keep_last.is_none() && self.keep_days.is_none() {
+            return Vec::new();
+        }
+
+        let mut by_bin: HashMap<String, Vec<Artifact>> = HashMap::new();
+        for artifact in artifacts {
+            by_bin
+                .entry(artifact.bin.clone())
+                .or_default()
+                .push(artifact);
+        }
+
+        let mut prunable = Vec::new();
+        for mut artifacts in by_bin.into_values() {
+            // Newest first. The modification time breaks ties
+            // between outputs built on the same day.
+            artifacts.sort_by(|a, b| {
+                (b.date, b.modified).cmp(&(a.date, a.modified))
+            });
+            for (index, artifact) in artifacts.into_iter().enumerate() {
+                let keep_by_count =
+                    self.keep_last.is_some_and(|keep| index < keep);
+                let age = (today - artifact.date).whole_days();
+                let keep_by_age =
+                    self.keep_days.is_some_and(|keep| age < i64::from(keep));
+                if !keep_by_count && !keep_by_age {
+                    prunable.push(artifact.path);
+                }
+            }
+        }
+        prunable.sort();
+        prunable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn date(day: u8) -> Date {
+        Date::from_calendar_date(2021, Month::March, day).unwrap()
+    }
+
+    #[test]
+    fn test_parse_artifact_name() {
+        let template = NameTemplate::default();
+        assert_eq!(
+            parse_artifact_name(
+                BuildMode::Lambda,
+                &template,
+                "lambda-my-bin-20210305-0123456789abcdef.zip"
+            ),
+            Some(("my-bin", Some(date(5))))
+        );
+        assert_eq!(
+            parse_artifact_name(
+                BuildMode::AmazonLinux2,
+                &template,
+                "al2-app-20210305-0123456789abcdef"
+            ),
+            Some(("app", Some(date(5))))
+        );
+        for name in [
+            "al2-app-20210305-0123456789abcdef",
+            "lambda-app-20210305-0123456789abcdef",
+            "lambda-app-20211305-0123456789abcdef.zip",
+            "lambda-app-2021030-0123456789abcdef.zip",
+            "lambda-20210305-0123456789abcdef.zip",
+            "lambda-cargo-registry",
+        ] {
+            assert_eq!(
+                parse_artifact_name(BuildMode::Lambda, &template, name),
+                None
+            );
+        }
+
+        let template = "{bin}_{version}_{git_sha}_{hash}".parse().unwrap();
+        assert_eq!(
+            parse_artifact_name(
+                BuildMode::AmazonLinux2,
+                &template,
+                "app_1.0.0_0123456789ab_0123456789abcdef"
+            ),
+            Some(("app", None))
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path();
+        let al2_dir = output_dir.join("al2");
+        fs::create_dir_all(al2_dir.join(codedeploy::BUNDLE_DIR)).unwrap();
+        let old = "al2-app-20210301-0123456789abcdef";
+        let new = "al2-app-20210302-0123456789abcdef";
+        let mut files = Vec::new();
+        for name in [old, new] {
+            files.extend([
+                al2_dir.join(name),
+                al2_dir.join(format!("{}.debug", name)),
+                al2_dir
+                    .join(codedeploy::BUNDLE_DIR)
+                    .join(format!("{}.zip", name)),
+            ]);
+        }
+        for path in &files {
+            fs::write(path, "").unwrap();
+        }
+
+        let retention = Retention {
+            keep_last: Some(1),
+            keep_days: None,
+        };
+        let deleted = retention
+            .prune(
+                BuildMode::AmazonLinux2,
+                &NameTemplate::default(),
+                output_dir,
+                None,
+            )
+            .unwrap();
+        assert_eq!(deleted, files[..3]);
+        for path in &files[3..] {
+            assert!(path.exists(), "{}", path.display());
+        }
+
+        let lambda_dir = output_dir.join("lambda");
+        fs::create_dir_all(lambda_dir.join(template::TEMPLATE_DIR)).unwrap();
+        let old = "lambda-app-20210301-0123456789abcdef";
+        let new = "lambda-app-20210302-0123456789abcdef";
+        let mut files = Vec::new();
+        for name in [old, new] {
+            files.extend([
+                lambda_dir.join(format!("{}.zip", name)),
+                lambda_dir
+                    .join(template::TEMPLATE_DIR)
+                    .join(format!("{}.yaml", name)),
+            ]);
+        }
+        for path in &files {
+            fs::write(path, "").unwrap();
+        }
+
+        let deleted = retention
+            .prune(
+                BuildMode::Lambda,
+                &NameTemplate::default(),
+                output_dir,
+                None,
+            )
+            .unwrap();
+        assert_eq!(deleted, files[..2]);
+        for path in &files[2..] {
+            assert!(path.exists(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let artifact = |bin: &str, day, secs| Artifact {
+            path: format!("{}-{}-{}", bin, day, secs).into(),
+            bin: bin.into(),
+            date: date(day),
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let artifacts = vec![
+            artifact("a", 1, 1),
+            artifact("a", 3, 2),
+            artifact("a", 3, 3),
+            artifact("a", 5, 4),
+            artifact("b", 1, 5),
+        ];
+        let select = |keep_last, keep_days| {
+            Retention {
+                keep_last,
+                keep_days,
+            }
+            .select(artifacts.clone(), date(5))
+        };
+        let paths = |paths: &[&str]| {
+            paths.iter().map(PathBuf::from).collect::<Vec<_>>()
+        };
+
+        assert_eq!(select(None, None), paths(&[]));
+        assert_eq!(select(Some(2), None), paths(&["a-1-1", "a-3-2"]));
+        assert_eq!(
+            select(None, Some(1)),
+            paths(&["a-1-1", "a-3-2", "a-3-3", "b-1-5"])
+        );
+        assert_eq!(select(Some(1), Some(3)), paths(&["a-1-1"]));
+    }
+}
@@ -0,0 +1,432 @@
+//! Upload build outputs to S3.
+
+use crate::{set_up_command, BuilderOutput};
+use anyhow::{anyhow, Context, Error};
+use docker_command::command_run::Command;
+use fehler::{throw, throws};
+use fs_err as fs;
+use log::info;
+use serde_json::{json, Value};
+use sha2::Digest;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Create an `aws` CLI command that runs `program`, using
+/// `endpoint_url` instead of the default AWS endpoint if set.
+pub(crate) fn aws_command(
+    program: &Path,
+    endpoint_url: Option<&str>,
+) -> Command {
+    let mut cmd = Command::new(program);
+    if let Some(url) = endpoint_url {
+        cmd.add_arg_pair("--endpoint-url", url);
+    }
+    cmd
+}
+
+/// Object in S3 written (or found to be up to date) by
+/// [`S3Publisher::publish`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PublishedObject {
+    /// Key of the object.
+    pub key: String,
+
+    /// Whether the object was uploaded. False if an object with the
+    /// same key and checksum already existed.
+    pub uploaded: bool,
+}
+
+/// Options for uploading build outputs to an S3 bucket.
+///
+/// The upload is done with the `aws` CLI, which must be installed
+/// and configured with credentials (e.g. with `aws configure` or the
+/// usual `AWS_*` environment variables).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct S3Publisher {
+    /// Bucket to upload to.
+    pub bucket: String,
+
+    /// Prefix of the object keys, e.g. `builds/`. A `/` is added
+    /// between the prefix and the file name if needed.
+    pub prefix: String,
+
+    /// URL of an S3-compatible service to use instead of AWS, e.g.
+    /// `http://localhost:9000` for a local MinIO server.
+    pub endpoint_url: Option<String>,
+
+    /// The `aws` CLI program to run, normally `aws` to find it in
+    /// `PATH`.
+    pub aws_program: PathBuf,
+}
+
+/// Get the sha256 hash of the file at `path` as hex, without reading
+/// the whole file into memory.
+#[throws]
+fn sha256_hex(path: &Path) -> String {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Get the name of the manifest for the output file `name`, which
+/// like the debug info file drops the `.zip` of Lambda outputs.
+fn manifest_name(name: &str) -> String {
+    format!(
+        "{}.manifest.json",
+        name.strip_suffix(".zip").unwrap_or(name)
+    )
+}
+
+/// Get the file name of `path` as a string.
+#[throws]
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("invalid path {}", path.display()))?
+}
+
+/// Check whether a failed `head-object` command failed because the
+/// object doesn't exist.
+fn is_not_found(stderr: &str) -> bool {
+    stderr.contains("(404)") || stderr.contains("Not Found")
+}
+
+/// File to upload: the object key, the file's path and size, and the
+/// hash of its contents.
+struct Upload {
+    key: String,
+    path: PathBuf,
+    size: u64,
+    sha256: String,
+}
+
+impl S3Publisher {
+    /// Get the key of the object for `file_name`.
+    fn key(&self, file_name: &str) -> String {
+        if self.prefix.is_empty() || self.prefix.ends_with('/') {
+            format!("{}{}", self.prefix, file_name)
+        } else {
+            format!("{}/{}", self.prefix, file_name)
+        }
+    }
+
+    /// Get the key that [`S3Publisher::publish`] uploads the output
+    /// file of `output` to.
+    #[throws]
+    pub fn output_key(&self, output: &BuilderOutput) -> String {
+        self.key(file_name(&output.real)?)
+    }
+
+    /// Prepare to upload the file at `path`.
+    #[throws]
+    fn upload_for_path(&self, path: &Path) -> Upload {
+        Upload {
+            key: self.key(file_name(path)?),
+            path: path.into(),
+            size: fs::metadata(path)?.len(),
+            sha256: sha256_hex(path)?,
+        }
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut cmd =
+            aws_command(&self.aws_program, self.endpoint_url.as_deref());
+        cmd.add_args(args);
+        cmd.add_arg_pair("--bucket", &self.bucket);
+        cmd
+    }
+
+    /// Get the sha256 hash recorded in the metadata of the object
+    /// `key`, or `None` if the object doesn't exist.
+    #[throws]
+    fn existing_sha256(&self, key: &str) -> Option<String> {
+        let mut cmd = self.command(&["s3api", "head-object"]);
+        cmd.add_arg_pair("--key", key);
+        cmd.add_arg_pair("--output", "json");
+        cmd.capture = true;
+        cmd.check = false;
+        cmd.log_command = false;
+        let output = cmd.run()?;
+        if !output.status.success() {
+            let stderr = output.stderr_string_lossy();
+            if is_not_found(&stderr) {
+                return None;
+            }
+            throw!(anyhow!("failed to check s3 object {}: {}", key, stderr));
+        }
+        let head: Value = serde_json::from_slice(&output.stdout)
+            .context("failed to parse head-object output")?;
+        head["Metadata"]["sha256"].as_str().map(Into::into)
+    }
+
+    /// Upload `upload` unless an object with the same key and
+    /// checksum already exists.
+    #[throws]
+    fn upload(&self, upload: &Upload) -> PublishedObject {
+        let url = format!("s3://{}/{}", self.bucket, upload.key);
+        let uploaded = if self.existing_sha256(&upload.key)?.as_deref()
+            == Some(upload.sha256.as_str())
+        {
+            info!("{} is already up to date", url);
+            false
+        } else {
+            info!("uploading {}", url);
+            let mut cmd = self.command(&["s3api", "put-object"]);
+            cmd.add_arg_pair("--key", &upload.key);
+            cmd.add_arg_pair("--body", &upload.path);
+            cmd.add_arg_pair("--metadata", format!("sha256={}", upload.sha256));
+            cmd.add_arg_pair("--output", "json");
+            cmd.capture = true;
+            set_up_command(&mut cmd);
+            cmd.run()?;
+            true
+        };
+        PublishedObject {
+            key: upload.key.clone(),
+            uploaded,
+        }
+    }
+
+    /// Upload the output of a build, its debug info file, RPM, and
+    /// CodeDeploy bundle if any, and a manifest. The manifest is a
+    /// JSON file listing the key, size, and sha256 hash of each
+    /// uploaded file, named after the output plus `.manifest.json`
+    /// (without `.zip` in Lambda mode).
+    ///
+    /// Files are only uploaded if there isn't already an object with
+    /// the same key and checksum. The checksum is stored in the
+    /// object's `sha256` metadata.
+    #[throws]
+    pub fn publish(&self, output: &BuilderOutput) -> Vec<PublishedObject> {
+        let mut uploads = vec![self.upload_for_path(&output.real)?];
+        for path in [&output.debug, &output.rpm, &output.codedeploy]
+            .into_iter()
+            .flatten()
+        {
+            uploads.push(self.upload_for_path(path)?);
+        }
+
+        let files: Vec<Value> = uploads
+            .iter()
+            .map(|upload| {
+                json!({
+                    "key": upload.key,
+                    "size": upload.size,
+                    "sha256": upload.sha256,
+                })
+            })
+            .collect();
+        let manifest = tempfile::NamedTempFile::new()?;
+        fs::write(
+            manifest.path(),
+            serde_json::to_vec_pretty(&json!({ "files": files }))?,
+        )?;
+        uploads.push(Upload {
+            key: self.key(&manifest_name(file_name(&output.real)?)),
+            ..self.upload_for_path(manifest.path())?
+        });
+
+        uploads
+            .iter()
+            .map(|upload| self.upload(upload))
+            .collect::<Result<_, _>>()?
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    /// Fake `aws` program that logs its arguments and then runs a
+    /// shell script.
+    pub(crate) struct FakeAws {
+        dir: TempDir,
+    }
+
+    impl FakeAws {
+        /// Create a fake that runs the shell script `body`.
+        pub(crate) fn new(body: &str) -> FakeAws {
+            let fake = FakeAws {
+                dir: tempfile::tempdir().unwrap(),
+            };
+            fs::write(
+                fake.program(),
+                format!(
+                    "#!/bin/sh\necho \"$*\" >> '{}'\n{}",
+                    fake.log_path().display(),
+                    body
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(fake.program(), Permissions::from_mode(0o755))
+                .unwrap();
+            fake
+        }
+
+        /// Path of the fake program.
+        pub(crate) fn program(&self) -> PathBuf {
+            self.dir.path().join("aws")
+        }
+
+        fn log_path(&self) -> PathBuf {
+            self.dir.path().join("log")
+        }
+
+        /// Get the logged command lines.
+        pub(crate) fn log(&self) -> Vec<String> {
+            fs::read_to_string(self.log_path())
+                .unwrap_or_default()
+                .lines()
+                .map(Into::into)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("lambda-a.zip");
+        fs::write(&real, "zip").unwrap();
+        let rpm = dir.path().join("a-1.0.0-1.x86_64.rpm");
+        fs::write(&rpm, "rpm").unwrap();
+        let output = BuilderOutput {
+            real: real.clone(),
+            symlink: None,
+            debug: None,
+            size: None,
+            rpm: Some(rpm),
+            codedeploy: None,
+            template: None,
+        };
+        let endpoint = "--endpoint-url http://localhost:9000 s3api";
+
+        // Nothing exists in the bucket yet
+        let aws = FakeAws::new(
+            "case \"$*\" in\n\
+             *head-object*) echo 'Not Found' >&2; exit 255;;\n\
+             *) echo '{}';;\n\
+             esac\n",
+        );
+        let publisher = S3Publisher {
+            bucket: "bucket".into(),
+            prefix: "builds".into(),
+            endpoint_url: Some("http://localhost:9000".into()),
+            aws_program: aws.program(),
+        };
+        let published = publisher.publish(&output).unwrap();
+        let log = aws.log();
+        assert_eq!(
+            published,
+            [
+                PublishedObject {
+                    key: "builds/lambda-a.zip".into(),
+                    uploaded: true,
+                },
+                PublishedObject {
+                    key: "builds/a-1.0.0-1.x86_64.rpm".into(),
+                    uploaded: true,
+                },
+                PublishedObject {
+                    key: "builds/lambda-a.manifest.json".into(),
+                    uploaded: true,
+                },
+            ]
+        );
+        let sha256 = sha256_hex(&real).unwrap();
+        assert_eq!(log.len(), 6, "{:?}", log);
+        assert_eq!(
+            log[0],
+            format!(
+                "{} head-object --bucket bucket --key builds/lambda-a.zip \
+                 --output json",
+                endpoint
+            )
+        );
+        assert_eq!(
+            log[1],
+            format!(
+                "{} put-object --bucket bucket --key builds/lambda-a.zip \
+                 --body {} --metadata sha256={} --output json",
+                endpoint,
+                real.display(),
+                sha256
+            )
+        );
+        assert!(log[5].starts_with(&format!(
+            "{} put-object --bucket bucket \
+             --key builds/lambda-a.manifest.json --body ",
+            endpoint
+        )));
+
+        // The zip is already up to date
+        let aws = FakeAws::new(&format!(
+            "case \"$*\" in\n\
+             *lambda-a.zip*) echo '{{\"Metadata\": {{\"sha256\": \"{}\"}}}}';;\n\
+             *head-object*) echo 'Not Found' >&2; exit 255;;\n\
+             *) echo '{{}}';;\n\
+             esac\n",
+            sha256
+        ));
+        let publisher = S3Publisher {
+            aws_program: aws.program(),
+            ..publisher
+        };
+        let published = publisher.publish(&output).unwrap();
+        let log = aws.log();
+        assert!(!published[0].uploaded);
+        assert!(published[1].uploaded);
+        assert!(published[2].uploaded);
+        assert_eq!(log.len(), 5, "{:?}", log);
+    }
+
+    #[test]
+    fn test_key() {
+        let publisher = |prefix: &str| S3Publisher {
+            bucket: "bucket".into(),
+            prefix: prefix.into(),
+            endpoint_url: None,
+            aws_program: "aws".into(),
+        };
+        assert_eq!(publisher("").key("a.zip"), "a.zip");
+        assert_eq!(publisher("builds").key("a.zip"), "builds/a.zip");
+        assert_eq!(publisher("builds/").key("a.zip"), "builds/a.zip");
+    }
+
+    #[test]
+    fn test_manifest_name() {
+        assert_eq!(manifest_name("lambda-a.zip"), "lambda-a.manifest.json");
+        assert_eq!(manifest_name("al2-a-1.0.0"), "al2-a-1.0.0.manifest.json");
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        assert!(is_not_found(
+            "An error occurred (404) when calling the HeadObject operation: \
+             Not Found"
+        ));
+        assert!(!is_not_found(
+            "An error occurred (403) when calling the HeadObject operation: \
+             Forbidden"
+        ));
+    }
+
+    #[test]
+    fn test_aws_command() {
+        assert_eq!(
+            aws_command(Path::new("aws"), Some("http://localhost:9000"))
+                .command_line_lossy(),
+            "aws --endpoint-url http://localhost:9000"
+        );
+    }
+}
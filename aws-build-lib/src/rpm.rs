@@ -0,0 +1,330 @@
+//! Package Amazon Linux 2 outputs as RPMs.
+
+use anyhow::{anyhow, Error};
+use cargo_metadata::Package;
+use fehler::{throw, throws};
+use fs_err as fs;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+
+/// Name of the directory in the mode's output directory where RPMs
+/// are built.
+pub(crate) const RPM_DIR: &str = "rpm";
+
+/// Name of the spec file in [`RPM_DIR`]`/SPECS`.
+pub(crate) const SPEC_NAME: &str = "package.spec";
+
+/// Options for packaging an Amazon Linux 2 output as an RPM.
+///
+/// The RPM's name, version, summary, and license are read from the
+/// package that contains the binary. It's built in the build image
+/// with `rpmbuild`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RpmOptions {
+    /// Directory to install the binary to.
+    pub install_dir: PathBuf,
+
+    /// Systemd unit file to install to `/usr/lib/systemd/system`.
+    pub systemd_unit: Option<PathBuf>,
+
+    /// Config files to install to `/etc/<package name>`. They are
+    /// marked as config files so that upgrades don't replace local
+    /// changes.
+    pub config_files: Vec<PathBuf>,
+
+    /// Release of the RPM. Defaults to the build's UTC time as
+    /// `YYYYMMDDHHMMSS`, so that a rebuild of the same cargo version
+    /// is seen as an upgrade.
+    pub release: Option<String>,
+}
+
+impl Default for RpmOptions {
+    fn default() -> Self {
+        RpmOptions {
+            install_dir: "/usr/bin".into(),
+            systemd_unit: None,
+            config_files: Vec::new(),
+            release: None,
+        }
+    }
+}
+
+/// Convert a cargo version to an RPM version, which can't contain
+/// `-`. A `~` sorts before the release, like a semver pre-release.
+fn rpm_version(version: &str) -> String {
+    version.replacen('-', "~", 1).replace('-', "_")
+}
+
+/// Get the default release for an RPM built at `when`.
+fn default_release(when: OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        when.year(),
+        u8::from(when.month()),
+        when.day(),
+        when.hour(),
+        when.minute(),
+        when.second()
+    )
+}
+
+/// Check that `release` is a valid RPM release, which can't contain
+/// `-` or whitespace.
+#[throws]
+fn check_release(release: &str) {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "._+~^".contains(c);
+    if release.is_empty() || !release.chars().all(valid) {
+        throw!(anyhow!("invalid RPM release: {:?}", release));
+    }
+}
+
+/// Get the file name of `path` as a string.
+#[throws]
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("invalid file name: {}", path.display()))?
+}
+
+/// Values written to the spec file.
+struct Spec<'a> {
+    name: &'a str,
+    version: String,
+    release: String,
+    summary: &'a str,
+    license: &'a str,
+    bin: &'a str,
+    install_dir: &'a str,
+    systemd_unit: Option<&'a str>,
+    config_files: Vec<&'a str>,
+}
+
+impl<'a> Spec<'a> {
+    #[throws]
+    fn new(
+        options: &'a RpmOptions,
+        package: &'a Package,
+        bin: &'a str,
+    ) -> Self {
+        let install_dir = options
+            .install_dir
+            .to_str()
+            .filter(|dir| dir.starts_with('/'))
+            .ok_or_else(|| anyhow!("RPM install directory must be absolute"))?;
+        let release = match &options.release {
+            Some(release) => release.clone(),
+            None => default_release(OffsetDateTime::now_utc()),
+        };
+        check_release(&release)?;
+        Spec {
+            name: &package.name,
+            version: rpm_version(&package.version.to_string()),
+            release,
+            summary: package
+                .description
+                .as_deref()
+                .and_then(|description| description.lines().next())
+                .unwrap_or(&package.name),
+            license: package.license.as_deref().unwrap_or("Unspecified"),
+            bin,
+            install_dir: install_dir.trim_end_matches('/'),
+            systemd_unit: options
+                .systemd_unit
+                .as_deref()
+                .map(file_name)
+                .transpose()?,
+            config_files: options
+                .config_files
+                .iter()
+                .map(|path| file_name(path))
+                .collect::<Result<_, _>>()?,
+        }
+    }
+
+    /// Create the spec file's contents.
+    fn render(&self) -> String {
+        let mut spec = String::new();
+        // Writing to a string can't fail
+        let mut line = |text: String| writeln!(spec, "{}", text).unwrap();
+
+        // A `%` in free text would start a macro
+        let summary = self.summary.replace('%', "%%");
+        let license = self.license.replace('%', "%%");
+
+        line(format!("Name: {}", self.name));
+        line(format!("Version: {}", self.version));
+        line(format!("Release: {}", self.release));
+        line(format!("Summary: {}", summary));
+        line(format!("License: {}", license));
+        // The binary is packaged as built; whether it's stripped is
+        // controlled by aws-build's options.
+        line("%global debug_package %{nil}".into());
+        line("%global __os_install_post %{nil}".into());
+        line(String::new());
+        line("%description".into());
+        line(summary);
+        line(String::new());
+
+        line("%install".into());
+        line(format!(
+            "install -D -m 755 %{{_sourcedir}}/{bin} %{{buildroot}}{dir}/{bin}",
+            bin = self.bin,
+            dir = self.install_dir
+        ));
+        if let Some(unit) = self.systemd_unit {
+            line(format!(
+                "install -D -m 644 %{{_sourcedir}}/{unit} \
+                 %{{buildroot}}/usr/lib/systemd/system/{unit}",
+                unit = unit
+            ));
+        }
+        for config in &self.config_files {
+            line(format!(
+                "install -D -m 644 %{{_sourcedir}}/config/{config} \
+                 %{{buildroot}}/etc/{name}/{config}",
+                config = config,
+                name = self.name
+            ));
+        }
+        line(String::new());
+
+        line("%files".into());
+        line(format!("{}/{}", self.install_dir, self.bin));
+        if let Some(unit) = self.systemd_unit {
+            line(format!("/usr/lib/systemd/system/{}", unit));
+        }
+        for config in &self.config_files {
+            line(format!("%config(noreplace) /etc/{}/{}", self.name, config));
+        }
+
+        if let Some(unit) = self.systemd_unit {
+            // The systemd RPM macros aren't available in the build
+            // image, so the scriptlets are written out
+            line(String::new());
+            line("%post".into());
+            line("systemctl daemon-reload >/dev/null 2>&1 || :".into());
+            line(String::new());
+            line("%preun".into());
+            line(format!(
+                "if [ $1 -eq 0 ]; then systemctl --no-reload disable --now \
+                 {} >/dev/null 2>&1 || :; fi",
+                unit
+            ));
+            line(String::new());
+            line("%postun".into());
+            line("systemctl daemon-reload >/dev/null 2>&1 || :".into());
+        }
+        spec
+    }
+}
+
+/// Write the spec file and copy the sources for `rpmbuild` into
+/// `rpm_dir`, replacing anything left from a previous build.
+#[throws]
+pub(crate) fn stage(
+    rpm_dir: &Path,
+    options: &RpmOptions,
+    package: &Package,
+    bin: &str,
+    bin_path: &Path,
+) {
+    let spec = Spec::new(options, package, bin)?;
+    let unique: BTreeSet<_> = spec.config_files.iter().collect();
+    if unique.len() != spec.config_files.len() {
+        throw!(anyhow!("RPM config file names must be unique"));
+    }
+
+    if rpm_dir.exists() {
+        fs::remove_dir_all(rpm_dir)?;
+    }
+    let sources = rpm_dir.join("SOURCES");
+    fs::create_dir_all(sources.join("config"))?;
+    fs::create_dir_all(rpm_dir.join("SPECS"))?;
+
+    fs::copy(bin_path, sources.join(bin))?;
+    if let Some(unit) = &options.systemd_unit {
+        fs::copy(unit, sources.join(file_name(unit)?))?;
+    }
+    for config in &options.config_files {
+        fs::copy(config, sources.join("config").join(file_name(config)?))?;
+    }
+    fs::write(rpm_dir.join("SPECS").join(SPEC_NAME), spec.render())?;
+}
+
+/// Find the RPM built in `rpm_dir`.
+#[throws]
+pub(crate) fn find_built_rpm(rpm_dir: &Path) -> PathBuf {
+    // RPMs are written to a subdirectory named after the architecture
+    for arch_dir in fs::read_dir(rpm_dir.join("RPMS"))? {
+        for entry in fs::read_dir(arch_dir?.path())? {
+            let path = entry?.path();
+            if path.extension() == Some("rpm".as_ref()) {
+                return path;
+            }
+        }
+    }
+    throw!(anyhow!("rpmbuild did not produce an RPM"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpm_version() {
+        assert_eq!(rpm_version("1.2.3"), "1.2.3");
+        assert_eq!(rpm_version("1.2.3-rc.1"), "1.2.3~rc.1");
+        assert_eq!(rpm_version("1.2.3-rc-1"), "1.2.3~rc_1");
+    }
+
+    #[test]
+    fn test_release() {
+        let when =
+            time::Date::from_calendar_date(2021, time::Month::November, 30)
+                .unwrap()
+                .with_hms(8, 5, 9)
+                .unwrap()
+                .assume_utc();
+        assert_eq!(default_release(when), "20211130080509");
+        assert!(check_release("1.el7").is_ok());
+        assert!(check_release("").is_err());
+        assert!(check_release("1-2").is_err());
+        assert!(check_release("1 2").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let spec = Spec {
+            name: "my-app",
+            version: "1.2.3".into(),
+            release: "20211130120000".into(),
+            summary: "My app, 100% Rust",
+            license: "MIT 100%",
+            bin: "my-app",
+            install_dir: "/usr/bin",
+            systemd_unit: Some("my-app.service"),
+            config_files: vec!["app.toml"],
+        };
+        let rendered = spec.render();
+        for expected in [
+            "Name: my-app\nVersion: 1.2.3\nRelease: 20211130120000\n",
+            "Summary: My app, 100%% Rust\nLicense: MIT 100%%\n",
+            "%description\nMy app, 100%% Rust\n",
+            "install -D -m 755 %{_sourcedir}/my-app \
+             %{buildroot}/usr/bin/my-app\n",
+            "%files\n/usr/bin/my-app\n/usr/lib/systemd/system/my-app.service\n\
+             %config(noreplace) /etc/my-app/app.toml\n",
+            "disable --now my-app.service",
+        ] {
+            assert!(rendered.contains(expected), "{}", rendered);
+        }
+
+        let spec = Spec {
+            systemd_unit: None,
+            ..spec
+        };
+        assert!(!spec.render().contains("%post"));
+    }
+}
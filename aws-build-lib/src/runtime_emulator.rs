@@ -0,0 +1,576 @@
+//! Minimal local implementation of the [Lambda runtime API].
+//!
+//! This can be used to test a Lambda `bootstrap` executable without
+//! deploying it. Start a [`RuntimeEmulator`], launch the executable
+//! with `AWS_LAMBDA_RUNTIME_API` set to the emulator's address, then
+//! queue events and wait for the results:
+//!
+//! ```no_run
+//! use aws_build_lib::runtime_emulator::{
+//!     InvocationResult, RuntimeEmulator, FUNCTION_NAME,
+//! };
+//! use std::process::Command;
+//! use std::time::Duration;
+//!
+//! let emulator = RuntimeEmulator::start(Duration::from_secs(3)).unwrap();
+//! // The binary that aws-build packages as `bootstrap`
+//! let mut child = Command::new("target/aws-build/lambda/release/my-function")
+//!     .env("AWS_LAMBDA_RUNTIME_API", emulator.addr().unwrap().to_string())
+//!     .env("AWS_LAMBDA_FUNCTION_NAME", FUNCTION_NAME)
+//!     .spawn()
+//!     .unwrap();
+//!
+//! let request_id = emulator.push_event(br#"{"name": "test"}"#.to_vec());
+//! let invocation = emulator
+//!     .wait_for_invocation(&request_id, Duration::from_secs(3))
+//!     .expect("no response");
+//! assert_eq!(
+//!     invocation.result,
+//!     InvocationResult::Response(br#"{"ok": true}"#.to_vec())
+//! );
+//! child.kill().unwrap();
+//! ```
+//!
+//! [Lambda runtime API]: https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html
+
+use anyhow::{anyhow, Error};
+use fehler::throws;
+use log::error;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Path prefix of all the runtime API endpoints.
+const API_PREFIX: &str = "/2018-06-01/runtime";
+
+/// Function name used in the ARN sent to the runtime with each
+/// event.
+pub const FUNCTION_NAME: &str = "aws-build";
+
+/// Result of a single invocation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InvocationResult {
+    /// The function returned a response.
+    Response(Vec<u8>),
+
+    /// The function reported an error.
+    Error(ErrorPayload),
+}
+
+/// Error reported by the function or runtime.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrorPayload {
+    /// Value of the `Lambda-Runtime-Function-Error-Type` header, if
+    /// set.
+    pub error_type: Option<String>,
+
+    /// Request body, normally a JSON object with `errorMessage` and
+    /// `errorType` fields.
+    pub body: Vec<u8>,
+}
+
+/// Completed invocation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Invocation {
+    /// Response or error sent by the function.
+    pub result: InvocationResult,
+
+    /// Time between the runtime receiving the event and sending the
+    /// result.
+    pub duration: Duration,
+}
+
+struct Event {
+    request_id: String,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_request_id: u64,
+
+    /// Events waiting to be picked up by the runtime.
+    pending: VecDeque<Event>,
+
+    /// Start time of events that have been picked up by the runtime
+    /// but not yet completed.
+    started: HashMap<String, Instant>,
+
+    /// Completed invocations that have not been retrieved yet.
+    completed: HashMap<String, Invocation>,
+
+    /// Error reported by the runtime during initialization.
+    init_error: Option<ErrorPayload>,
+
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct State {
+    inner: Mutex<Inner>,
+    changed: Condvar,
+}
+
+impl State {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // The state is always left consistent, so a panic in another
+        // thread doesn't matter.
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Lambda runtime API server.
+///
+/// The server listens on a random port on localhost and runs until it
+/// is dropped. It implements these endpoints:
+///
+/// * `GET /2018-06-01/runtime/invocation/next`
+/// * `POST /2018-06-01/runtime/invocation/{request_id}/response`
+/// * `POST /2018-06-01/runtime/invocation/{request_id}/error`
+/// * `POST /2018-06-01/runtime/init/error`
+pub struct RuntimeEmulator {
+    server: Arc<Server>,
+    state: Arc<State>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RuntimeEmulator {
+    /// Start the server. The `timeout` is used to calculate the
+    /// deadline passed to the function with each event.
+    #[throws]
+    pub fn start(timeout: Duration) -> Self {
+        let server = Server::http("127.0.0.1:0").map_err(|err| {
+            anyhow!("failed to start runtime API server: {}", err)
+        })?;
+        let server = Arc::new(server);
+        let state = Arc::new(State::default());
+
+        let thread = {
+            let server = server.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    // Each request is handled in its own thread since
+                    // the "next" request blocks until an event is
+                    // available.
+                    let state = state.clone();
+                    thread::spawn(move || {
+                        if let Err(err) =
+                            handle_request(&state, timeout, request)
+                        {
+                            error!("runtime API request failed: {}", err);
+                        }
+                    });
+                }
+            })
+        };
+
+        Self {
+            server,
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    /// Address the server is listening on. This is the value to set
+    /// as `AWS_LAMBDA_RUNTIME_API` for the runtime.
+    #[throws]
+    pub fn addr(&self) -> SocketAddr {
+        self.server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| anyhow!("runtime API server has no IP address"))?
+    }
+
+    /// Queue an event for the runtime to pick up. Returns the request
+    /// ID of the invocation.
+    pub fn push_event(&self, payload: Vec<u8>) -> String {
+        let mut inner = self.state.lock();
+        let request_id =
+            format!("00000000-0000-0000-0000-{:012}", inner.next_request_id);
+        inner.next_request_id += 1;
+        inner.pending.push_back(Event {
+            request_id: request_id.clone(),
+            payload,
+        });
+        self.state.changed.notify_all();
+        request_id
+    }
+
+    /// Wait up to `timeout` for the invocation with `request_id` to
+    /// complete. Returns `None` if it does not complete in time, or if
+    /// the runtime reported an initialization error (see
+    /// [`init_error`]).
+    ///
+    /// [`init_error`]: Self::init_error
+    pub fn wait_for_invocation(
+        &self,
+        request_id: &str,
+        timeout: Duration,
+    ) -> Option<Invocation> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.state.lock();
+        loop {
+            if let Some(invocation) = inner.completed.remove(request_id) {
+                return Some(invocation);
+            }
+            if inner.init_error.is_some() {
+                return None;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            inner = self
+                .state
+                .changed
+                .wait_timeout(inner, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Get the error sent by the runtime to the init error endpoint,
+    /// if any.
+    pub fn init_error(&self) -> Option<ErrorPayload> {
+        self.state.lock().init_error.clone()
+    }
+}
+
+impl Drop for RuntimeEmulator {
+    fn drop(&mut self) {
+        // Wake up any requests waiting for an event
+        self.state.lock().shutdown = true;
+        self.state.changed.notify_all();
+
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[throws]
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value)
+        .map_err(|()| anyhow!("invalid header: {}: {}", name, value))?
+}
+
+#[throws]
+fn read_body(request: &mut Request) -> Vec<u8> {
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body)?;
+    body
+}
+
+#[throws]
+fn read_error(request: &mut Request) -> ErrorPayload {
+    let error_type = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Lambda-Runtime-Function-Error-Type"))
+        .map(|h| h.value.to_string());
+    let body = read_body(request)?;
+    ErrorPayload { error_type, body }
+}
+
+#[throws]
+fn respond_json(request: Request, status: u16, body: &str) {
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json")?);
+    request.respond(response)?;
+}
+
+#[throws]
+fn handle_request(state: &State, timeout: Duration, mut request: Request) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let path = url.strip_prefix(API_PREFIX).unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+
+    match (method, segments.as_slice()) {
+        (Method::Get, ["invocation", "next"]) => {
+            next_event(state, timeout, request)?
+        }
+        (Method::Post, ["invocation", request_id, "response"]) => {
+            let body = read_body(&mut request)?;
+            let result = InvocationResult::Response(body);
+            complete_invocation(state, request_id, result, request)?;
+        }
+        (Method::Post, ["invocation", request_id, "error"]) => {
+            let result = InvocationResult::Error(read_error(&mut request)?);
+            complete_invocation(state, request_id, result, request)?;
+        }
+        (Method::Post, ["init", "error"]) => {
+            let payload = read_error(&mut request)?;
+            state.lock().init_error = Some(payload);
+            state.changed.notify_all();
+            respond_json(request, 202, r#"{"status":"OK"}"#)?;
+        }
+        _ => respond_json(
+            request,
+            404,
+            r#"{"errorMessage":"not found","errorType":"NotFound"}"#,
+        )?,
+    }
+}
+
+/// Handle the "next" endpoint by waiting for an event and sending it
+/// to the runtime.
+#[throws]
+fn next_event(state: &State, timeout: Duration, request: Request) {
+    let mut inner = state.lock();
+    let event = loop {
+        if inner.shutdown {
+            return respond_json(
+                request,
+                503,
+                r#"{"errorMessage":"shutting down","errorType":"ServiceUnavailable"}"#,
+            )?;
+        }
+        if let Some(event) = inner.pending.pop_front() {
+            break event;
+        }
+        inner = state
+            .changed
+            .wait(inner)
+            .unwrap_or_else(PoisonError::into_inner);
+    };
+    inner
+        .started
+        .insert(event.request_id.clone(), Instant::now());
+    drop(inner);
+
+    let deadline = SystemTime::now().duration_since(UNIX_EPOCH)? + timeout;
+    let arn = format!(
+        "arn:aws:lambda:us-east-1:000000000000:function:{}",
+        FUNCTION_NAME
+    );
+    let response = Response::from_data(event.payload)
+        .with_header(header("Content-Type", "application/json")?)
+        .with_header(header(
+            "Lambda-Runtime-Aws-Request-Id",
+            &event.request_id,
+        )?)
+        .with_header(header(
+            "Lambda-Runtime-Deadline-Ms",
+            &deadline.as_millis().to_string(),
+        )?)
+        .with_header(header("Lambda-Runtime-Invoked-Function-Arn", &arn)?)
+        .with_header(header(
+            "Lambda-Runtime-Trace-Id",
+            &format!("Root=1-00000000-{}", event.request_id.replace('-', "")),
+        )?);
+    request.respond(response)?;
+}
+
+/// Record the result of an invocation.
+#[throws]
+fn complete_invocation(
+    state: &State,
+    request_id: &str,
+    result: InvocationResult,
+    request: Request,
+) {
+    let mut inner = state.lock();
+    let started = if let Some(started) = inner.started.remove(request_id) {
+        started
+    } else {
+        drop(inner);
+        return respond_json(
+            request,
+            400,
+            r#"{"errorMessage":"invalid request ID","errorType":"InvalidRequestID"}"#,
+        )?;
+    };
+    inner.completed.insert(
+        request_id.to_string(),
+        Invocation {
+            result,
+            duration: started.elapsed(),
+        },
+    );
+    state.changed.notify_all();
+    drop(inner);
+
+    respond_json(request, 202, r#"{"status":"OK"}"#)?;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    struct TestResponse {
+        status: u16,
+        head: String,
+        body: String,
+    }
+
+    /// Send a single HTTP request to the emulator.
+    fn send(
+        emulator: &RuntimeEmulator,
+        method: &str,
+        path: &str,
+        headers: &[&str],
+        body: &str,
+    ) -> TestResponse {
+        let mut stream = TcpStream::connect(emulator.addr().unwrap()).unwrap();
+        let mut request = format!(
+            "{} {}{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            API_PREFIX,
+            path,
+            body.len()
+        );
+        for header in headers {
+            request.push_str(header);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        TestResponse {
+            status: head[9..12].parse().unwrap(),
+            head: head.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    fn start() -> RuntimeEmulator {
+        RuntimeEmulator::start(Duration::from_secs(10)).unwrap()
+    }
+
+    #[test]
+    fn test_response() {
+        let emulator = start();
+        let request_id = emulator.push_event(b"{}".to_vec());
+
+        let next = send(&emulator, "GET", "/invocation/next", &[], "");
+        assert_eq!(next.status, 200);
+        assert_eq!(next.body, "{}");
+        assert!(next.head.contains(&format!(
+            "Lambda-Runtime-Aws-Request-Id: {}",
+            request_id
+        )));
+        assert!(next.head.contains("Lambda-Runtime-Deadline-Ms: "));
+
+        let path = format!("/invocation/{}/response", request_id);
+        let response = send(&emulator, "POST", &path, &[], "ok");
+        assert_eq!(response.status, 202);
+
+        let invocation = emulator
+            .wait_for_invocation(&request_id, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            invocation.result,
+            InvocationResult::Response(b"ok".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        let emulator = start();
+        let request_id = emulator.push_event(b"{}".to_vec());
+        send(&emulator, "GET", "/invocation/next", &[], "");
+
+        let path = format!("/invocation/{}/error", request_id);
+        let body = r#"{"errorMessage":"oops","errorType":"Oops"}"#;
+        let headers = ["Lambda-Runtime-Function-Error-Type: Unhandled"];
+        let response = send(&emulator, "POST", &path, &headers, body);
+        assert_eq!(response.status, 202);
+
+        let invocation = emulator
+            .wait_for_invocation(&request_id, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            invocation.result,
+            InvocationResult::Error(ErrorPayload {
+                error_type: Some("Unhandled".into()),
+                body: body.as_bytes().to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_init_error() {
+        let emulator = start();
+        let request_id = emulator.push_event(b"{}".to_vec());
+
+        let body = r#"{"errorMessage":"bad config","errorType":"Config"}"#;
+        let response = send(&emulator, "POST", "/init/error", &[], body);
+        assert_eq!(response.status, 202);
+
+        assert_eq!(
+            emulator.wait_for_invocation(&request_id, Duration::from_secs(10)),
+            None
+        );
+        assert_eq!(
+            emulator.init_error(),
+            Some(ErrorPayload {
+                error_type: None,
+                body: body.as_bytes().to_vec(),
+            })
+        );
+    }
+
+    /// Test a trivial handler that runs the runtime loop like a
+    /// `bootstrap` executable, responding to each event with the
+    /// payload in uppercase.
+    #[test]
+    fn test_handler() {
+        let emulator = start();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..2 {
+                    let next =
+                        send(&emulator, "GET", "/invocation/next", &[], "");
+                    let request_id = next
+                        .head
+                        .lines()
+                        .find_map(|line| {
+                            line.strip_prefix("Lambda-Runtime-Aws-Request-Id: ")
+                        })
+                        .unwrap();
+                    let path = format!("/invocation/{}/response", request_id);
+                    let body = next.body.to_uppercase();
+                    send(&emulator, "POST", &path, &[], &body);
+                }
+            });
+
+            for event in ["first", "second"] {
+                let request_id = emulator.push_event(event.into());
+                let invocation = emulator
+                    .wait_for_invocation(&request_id, Duration::from_secs(10))
+                    .unwrap();
+                assert_eq!(
+                    invocation.result,
+                    InvocationResult::Response(
+                        event.to_uppercase().into_bytes()
+                    )
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_invalid_request_id() {
+        let emulator = start();
+        let response =
+            send(&emulator, "POST", "/invocation/bad/response", &[], "ok");
+        assert_eq!(response.status, 400);
+        assert!(response.body.contains("InvalidRequestID"));
+
+        let response = send(&emulator, "GET", "/invalid", &[], "");
+        assert_eq!(response.status, 404);
+    }
+}
@@ -0,0 +1,310 @@
+//! Report the size of build outputs.
+
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use object::{BinaryFormat, Object, ObjectSection, ObjectSymbol, SymbolKind};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Code size of one crate in the binary.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CrateSize {
+    /// Crate name. Symbols that aren't Rust-mangled are grouped under
+    /// `[other]`, and Rust symbols whose crate can't be worked out
+    /// under `[unknown]`.
+    pub name: String,
+
+    /// Total size in bytes of the crate's functions.
+    pub size: u64,
+}
+
+/// Sizes of a build output, returned in [`BuilderOutput::size`].
+///
+/// [`BuilderOutput::size`]: crate::BuilderOutput::size
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SizeReport {
+    /// Size of the output file. In Lambda mode this is the zip file.
+    pub total: u64,
+
+    /// Size of the binary in the output, after any stripping.
+    pub binary: u64,
+
+    /// Size of the binary built by cargo, before any stripping.
+    pub unstripped: u64,
+
+    /// Estimated size of the binary with its symbols and debug info
+    /// removed.
+    pub stripped: u64,
+
+    /// Compressed size of the binary in the zip file (Lambda only).
+    pub zip: Option<u64>,
+
+    /// Code size of each crate, largest first, read from the symbol
+    /// table of the binary built by cargo. Empty if it has no symbol
+    /// table, e.g. if cargo was configured to strip it.
+    pub crates: Vec<CrateSize>,
+}
+
+/// Number of crates shown by the [`SizeReport`]'s `Display` impl.
+const TOP_CRATES: usize = 10;
+
+/// Format a size in bytes for humans.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{:.1} {}", value, unit)
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "size: {} (binary {}, unstripped {}, stripped {}",
+            format_size(self.total),
+            format_size(self.binary),
+            format_size(self.unstripped),
+            format_size(self.stripped),
+        )?;
+        if let Some(zip) = self.zip {
+            write!(f, ", compressed {}", format_size(zip))?;
+        }
+        write!(f, ")")?;
+        if !self.crates.is_empty() {
+            write!(f, "\ntop crates by code size:")?;
+            for krate in self.crates.iter().take(TOP_CRATES) {
+                write!(
+                    f,
+                    "\n  {:>10}  {}",
+                    format_size(krate.size),
+                    krate.name
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Information read from an ELF binary.
+#[derive(Debug, Default)]
+pub(crate) struct ElfInfo {
+    /// Total size of the sections that stripping removes: the symbol
+    /// table, its string table, and the debug info.
+    pub(crate) strippable: u64,
+
+    /// Name and size of each function in the symbol table.
+    pub(crate) functions: Vec<(String, u64)>,
+}
+
+/// Read the section sizes and function symbols of an ELF binary.
+#[throws]
+pub(crate) fn read_elf(data: &[u8]) -> ElfInfo {
+    let file = object::File::parse(data)?;
+    if file.format() != BinaryFormat::Elf {
+        throw!(anyhow!("not an ELF binary"));
+    }
+
+    let mut info = ElfInfo::default();
+    for section in file.sections() {
+        let name = section.name()?;
+        // Sections that take no space in the file have no file range
+        if let Some((_, size)) = section.file_range() {
+            if name == ".symtab"
+                || name == ".strtab"
+                || name.starts_with(".debug")
+            {
+                info.strippable += size;
+            }
+        }
+    }
+    for symbol in file.symbols() {
+        if symbol.kind() != SymbolKind::Text || symbol.size() == 0 {
+            continue;
+        }
+        info.functions.push((symbol.name()?.into(), symbol.size()));
+    }
+    info
+}
+
+/// Parse a decimal length followed by an identifier of that length
+/// from the start of `s`.
+fn parse_ident(s: &str) -> Option<&str> {
+    let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = s[..digits].parse().ok()?;
+    s[digits..].get(..len)
+}
+
+/// Get the crate of a symbol mangled with the legacy scheme, e.g.
+/// `_ZN4core3fmt5write17h0123456789abcdefE`.
+fn legacy_crate_name(symbol: &str) -> Option<String> {
+    let ident = parse_ident(symbol.strip_prefix("_ZN")?)?;
+    // Impls are written as e.g. `_<impl Trait for crate::Type>`, in
+    // which case the type's crate is used
+    let mut ident = ident.trim_start_matches('_');
+    if let Some(rest) = ident.strip_prefix("$LT$") {
+        ident = rest;
+        for prefix in ["$RF$", "$BP$", "mut$u20$", "dyn$u20$"] {
+            ident = ident.trim_start_matches(prefix);
+        }
+    }
+    let end = ident.find(['.', '$']).unwrap_or(ident.len());
+    let name = &ident[..end];
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.into())
+    }
+}
+
+/// Get the crate of a symbol mangled with the v0 scheme, e.g.
+/// `_RNvCs1234_7mycrate4main`. Only the outer path is decoded, so
+/// some generic symbols aren't recognized.
+fn v0_crate_name(symbol: &str) -> Option<String> {
+    let mut rest = symbol.strip_prefix("_R")?;
+    rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+    loop {
+        let mut chars = rest.chars();
+        match chars.next()? {
+            // Nested path, followed by a namespace
+            'N' => {
+                chars.next()?;
+                rest = chars.as_str();
+            }
+            // Impls and generic arguments, followed by a path
+            'M' | 'X' | 'Y' | 'I' => rest = chars.as_str(),
+            // Crate root, with an optional disambiguator
+            'C' => {
+                let mut rest = chars.as_str();
+                if let Some(after) = rest.strip_prefix('s') {
+                    rest = &after[after.find('_')? + 1..];
+                }
+                // Punycode identifiers have a `u` prefix, and a `_`
+                // separates the length from an identifier that starts
+                // with a digit or `_`
+                rest = rest.strip_prefix('u').unwrap_or(rest);
+                let digits =
+                    rest.bytes().take_while(u8::is_ascii_digit).count();
+                let len: usize = rest[..digits].parse().ok()?;
+                rest = &rest[digits..];
+                rest = rest.strip_prefix('_').unwrap_or(rest);
+                return rest.get(..len).map(Into::into);
+            }
+            // Disambiguator of an impl path
+            's' => rest = &rest[rest.find('_')? + 1..],
+            _ => return None,
+        }
+    }
+}
+
+/// Get the name of the crate that a symbol belongs to, see
+/// [`CrateSize::name`].
+fn crate_name(symbol: &str) -> String {
+    let name = if symbol.starts_with("_ZN") {
+        legacy_crate_name(symbol)
+    } else if symbol.starts_with("_R") {
+        v0_crate_name(symbol)
+    } else {
+        return "[other]".into();
+    };
+    name.unwrap_or_else(|| "[unknown]".into())
+}
+
+/// Sum the size of `functions` by crate, largest first.
+pub(crate) fn crate_sizes(functions: &[(String, u64)]) -> Vec<CrateSize> {
+    let mut sizes: HashMap<String, u64> = HashMap::new();
+    for (symbol, size) in functions {
+        *sizes.entry(crate_name(symbol)).or_default() += size;
+    }
+    let mut crates: Vec<_> = sizes
+        .into_iter()
+        .map(|(name, size)| CrateSize { name, size })
+        .collect();
+    crates.sort_by(|a, b| b.size.cmp(&a.size).then(a.name.cmp(&b.name)));
+    crates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs_err as fs;
+
+    #[test]
+    fn test_crate_name() {
+        for (symbol, expected) in [
+            ("_ZN4core3fmt5write17h0123456789abcdefE", "core"),
+            ("_ZN8my_crate4main17h0123456789abcdefE", "my_crate"),
+            (
+                "_ZN60_$LT$alloc..string..String$u20$as$u20$core..fmt..\
+                 Display$GT$3fmt17h0123456789abcdefE",
+                "alloc",
+            ),
+            ("_RNvCs1234_7mycrate4main", "mycrate"),
+            ("_RNvNtCsd4o4Kf6ijeN_4core3fmt5write", "core"),
+            ("_RNvMNtCs1_5alloc3vecINtB2_3VecpE4push", "alloc"),
+            ("_RNvC7mycrate4main", "mycrate"),
+            ("_RNvC8_0mycrate4main", "0mycrate"),
+            ("_ZNgarbage", "[unknown]"),
+            ("_RQ", "[unknown]"),
+            ("memcpy", "[other]"),
+        ] {
+            assert_eq!(crate_name(symbol), expected, "{}", symbol);
+        }
+    }
+
+    #[test]
+    fn test_crate_sizes() {
+        let functions = [
+            ("_ZN4core3fmt5write17hE".to_string(), 10),
+            ("_ZN3std2io5stdin17hE".to_string(), 30),
+            ("_ZN4core3ptr4read17hE".to_string(), 25),
+            ("memcpy".to_string(), 5),
+        ];
+        assert_eq!(
+            crate_sizes(&functions),
+            vec![
+                CrateSize {
+                    name: "core".into(),
+                    size: 35
+                },
+                CrateSize {
+                    name: "std".into(),
+                    size: 30
+                },
+                CrateSize {
+                    name: "[other]".into(),
+                    size: 5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_elf() {
+        assert!(read_elf(b"not an elf").is_err());
+
+        // The test binary itself is an unstripped ELF binary
+        let data = fs::read(std::env::current_exe().unwrap()).unwrap();
+        let info = read_elf(&data).unwrap();
+        assert!(info.strippable > 0);
+        let crates = crate_sizes(&info.functions);
+        assert!(crates.iter().any(|krate| krate.name == "std"));
+        assert!(crates.iter().any(|krate| krate.name == "aws_build_lib"));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+    }
+}
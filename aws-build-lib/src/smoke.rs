@@ -0,0 +1,59 @@
+//! Run a built binary in a stock Amazon Linux 2 container.
+
+use crate::{set_up_command, AMAZON_LINUX_2_IMAGE};
+use anyhow::{anyhow, Error};
+use docker_command::{Launcher, RunOpt, Volume};
+use fehler::throws;
+use fs_err as fs;
+use std::path::{Path, PathBuf};
+
+/// Options for running an Amazon Linux 2 binary in a clean
+/// `amazonlinux:2` container.
+///
+/// Unlike the build container, this container has no Rust toolchain
+/// or dev packages installed. This checks that the binary starts with
+/// a stock runtime environment.
+#[must_use]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SmokeRunner {
+    /// Container launcher.
+    pub launcher: Launcher,
+
+    /// Path of the binary to run, e.g. `target/latest-al2`.
+    pub binary_path: PathBuf,
+
+    /// Arguments to pass to the binary.
+    pub args: Vec<String>,
+}
+
+impl SmokeRunner {
+    /// Run the binary and wait for it to exit. An error is returned if
+    /// it exits non-zero.
+    #[throws]
+    pub fn run(&self) {
+        // Resolve the `latest-*` symlink so that the real file is
+        // mounted.
+        let binary_path = fs::canonicalize(&self.binary_path)?;
+        let file_name = binary_path
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid binary path"))?;
+        let dst = Path::new("/usr/local/bin").join(file_name);
+
+        let mut cmd = self.launcher.run(RunOpt {
+            remove: true,
+            init: true,
+            volumes: vec![Volume {
+                src: binary_path.clone(),
+                dst: dst.clone(),
+                read_write: false,
+                options: Vec::new(),
+            }],
+            image: AMAZON_LINUX_2_IMAGE.into(),
+            command: Some(dst),
+            args: self.args.iter().map(Into::into).collect(),
+            ..Default::default()
+        });
+        set_up_command(&mut cmd);
+        cmd.run()?;
+    }
+}
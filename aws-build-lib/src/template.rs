@@ -0,0 +1,157 @@
+//! Generate template snippets for deploying Lambda outputs.
+
+use crate::naming::arch_name;
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Name of the directory in the mode's output directory where
+/// snippets are written.
+pub(crate) const TEMPLATE_DIR: &str = "templates";
+
+/// Kind of template snippet to write for a Lambda output.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TemplateKind {
+    /// An `AWS::Serverless::Function` resource for a SAM template,
+    /// with `CodeUri` set to the zip file's path.
+    Sam,
+
+    /// An `AWS::Lambda::Function` resource for a CloudFormation
+    /// template, with `S3Key` set to the zip file's name. The bucket
+    /// and execution role are referenced as `CodeBucket` and e.g.
+    /// `MyAppFunctionRole` (for `my-app`), which the template must
+    /// define.
+    CloudFormation,
+}
+
+impl FromStr for TemplateKind {
+    type Err = Error;
+
+    #[throws]
+    fn from_str(s: &str) -> Self {
+        match s {
+            "sam" => TemplateKind::Sam,
+            "cloudformation" => TemplateKind::CloudFormation,
+            _ => throw!(anyhow!(
+                "invalid template kind {}, expected sam or cloudformation",
+                s
+            )),
+        }
+    }
+}
+
+/// Convert a binary name to a resource logical ID, which must be
+/// alphanumeric, e.g. `my-app` becomes `MyAppFunction`.
+fn logical_id(bin: &str) -> String {
+    let mut id = String::new();
+    for word in bin.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            id.push(first.to_ascii_uppercase());
+            id.extend(chars);
+        }
+    }
+    id + "Function"
+}
+
+/// Quote a string for YAML.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Create a snippet for the Lambda function `bin` whose code is in
+/// the zip file at `zip_path`.
+#[throws]
+pub(crate) fn render(kind: TemplateKind, bin: &str, zip_path: &Path) -> String {
+    let zip_name = zip_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("invalid zip path {}", zip_path.display()))?;
+    let id = logical_id(bin);
+
+    let (resource_type, code) = match kind {
+        TemplateKind::Sam => (
+            "AWS::Serverless::Function",
+            format!(
+                "    CodeUri: {}\n",
+                quote(
+                    zip_path
+                        .to_str()
+                        .ok_or_else(|| anyhow!("zip path is not utf-8"))?
+                )
+            ),
+        ),
+        TemplateKind::CloudFormation => (
+            "AWS::Lambda::Function",
+            format!(
+                "    Code:\n      S3Bucket: !Ref CodeBucket\n      \
+                 S3Key: {}\n    Role: !GetAtt {}Role.Arn\n",
+                quote(zip_name),
+                id
+            ),
+        ),
+    };
+    format!(
+        "# Generated by aws-build for {zip_name}\n\
+         {id}:\n  Type: {resource_type}\n  Properties:\n\
+         {code}    Handler: bootstrap\n    Runtime: provided.al2\n    \
+         Architectures:\n      - {arch}\n",
+        zip_name = zip_name,
+        id = id,
+        resource_type = resource_type,
+        code = code,
+        arch = arch_name(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logical_id() {
+        assert_eq!(logical_id("app"), "AppFunction");
+        assert_eq!(logical_id("my-app_2"), "MyApp2Function");
+    }
+
+    #[test]
+    fn test_render() {
+        let zip_path = Path::new("/out/lambda/lambda-my-app-20210305-01.zip");
+        assert_eq!(
+            render(TemplateKind::Sam, "my-app", zip_path).unwrap(),
+            format!(
+                "# Generated by aws-build for lambda-my-app-20210305-01.zip
+MyAppFunction:
+  Type: AWS::Serverless::Function
+  Properties:
+    CodeUri: '/out/lambda/lambda-my-app-20210305-01.zip'
+    Handler: bootstrap
+    Runtime: provided.al2
+    Architectures:
+      - {}
+",
+                arch_name()
+            )
+        );
+        assert_eq!(
+            render(TemplateKind::CloudFormation, "my-app", zip_path).unwrap(),
+            format!(
+                "# Generated by aws-build for lambda-my-app-20210305-01.zip
+MyAppFunction:
+  Type: AWS::Lambda::Function
+  Properties:
+    Code:
+      S3Bucket: !Ref CodeBucket
+      S3Key: 'lambda-my-app-20210305-01.zip'
+    Role: !GetAtt MyAppFunctionRole.Arn
+    Handler: bootstrap
+    Runtime: provided.al2
+    Architectures:
+      - {}
+",
+                arch_name()
+            )
+        );
+    }
+}
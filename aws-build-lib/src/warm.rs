@@ -0,0 +1,234 @@
+//! Build containers that are kept running between builds.
+//!
+//! Each build is run in the container with `exec`, which avoids
+//! paying the container startup cost every time.
+
+use crate::{set_up_command, Container};
+use anyhow::{anyhow, Error};
+use docker_command::command_run::Command;
+use docker_command::Launcher;
+use fehler::{throw, throws};
+use log::info;
+use sha2::Digest;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a container to start.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Name prefix of containers started by [`start_daemon`].
+const DAEMON_PREFIX: &str = "aws-build-daemon-";
+
+/// Create a command that is run quietly and whose failure is not an
+/// error.
+fn quiet_command(launcher: &Launcher) -> Command {
+    let mut cmd = launcher.base_command().clone();
+    cmd.log_command = false;
+    cmd.capture = true;
+    cmd.check = false;
+    cmd
+}
+
+/// Check whether the named container is running.
+#[throws]
+fn is_running(launcher: &Launcher, name: &str) -> bool {
+    let mut cmd = quiet_command(launcher);
+    cmd.add_args(["inspect", "--format", "{{.State.Running}}", name]);
+    let output = cmd.run()?;
+    output.status.success() && output.stdout_string_lossy().trim() == "true"
+}
+
+/// Wait for the named container to be running. If `child` is set,
+/// fail early if it exits.
+#[throws]
+fn wait_until_running(
+    launcher: &Launcher,
+    name: &str,
+    mut child: Option<&mut process::Child>,
+) {
+    let start = Instant::now();
+    while !is_running(launcher, name)? {
+        if let Some(child) = child.as_mut() {
+            if let Some(status) = child.try_wait()? {
+                throw!(anyhow!("container exited during startup: {}", status));
+            }
+        }
+        if start.elapsed() > START_TIMEOUT {
+            throw!(anyhow!(
+                "container did not start within {:?}",
+                START_TIMEOUT
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Build `bin` in the named container, which must have been started
+/// with [`CargoCommand::Idle`].
+///
+/// [`CargoCommand::Idle`]: crate::CargoCommand::Idle
+#[throws]
+pub(crate) fn exec_build(container: &Container, name: &str, bin: &str) {
+    container.with_output_permissions(|| {
+        let mut cmd = container.launcher.base_command().clone();
+        cmd.add_args(["exec", "--env", "CARGO_COMMAND=build"]);
+        cmd.add_arg_pair("--env", format!("BIN_TARGET={}", bin));
+        cmd.add_args([name, "/build.sh"]);
+        set_up_command(&mut cmd);
+        cmd.run()?;
+        Ok(())
+    })?;
+}
+
+/// A container that runs in the foreground for the lifetime of this
+/// object. It is stopped when this is dropped.
+pub(crate) struct WarmContainer<'a> {
+    launcher: &'a Launcher,
+    pub(crate) name: String,
+    child: process::Child,
+}
+
+impl<'a> WarmContainer<'a> {
+    /// Start the container and wait for it to be running. The
+    /// container must have been created with [`CargoCommand::Idle`].
+    ///
+    /// [`CargoCommand::Idle`]: crate::CargoCommand::Idle
+    #[throws]
+    pub(crate) fn start(container: &Container<'a>) -> Self {
+        let name = format!("aws-build-watch-{}", process::id());
+        let mut opt = container.run_opt()?;
+        opt.name = Some(name.clone());
+
+        // Run in the foreground as a child process so that the
+        // container gets stopped if this process is interrupted.
+        let cmd = container.launcher.run(opt);
+        info!("{}", cmd.command_line_lossy());
+        let mut child = process::Command::from(&cmd).spawn()?;
+
+        if let Err(err) =
+            wait_until_running(container.launcher, &name, Some(&mut child))
+        {
+            let _ = child.kill();
+            throw!(err);
+        }
+        WarmContainer {
+            launcher: container.launcher,
+            name,
+            child,
+        }
+    }
+}
+
+impl<'a> Drop for WarmContainer<'a> {
+    fn drop(&mut self) {
+        // Errors are ignored since the container may have already
+        // stopped.
+        let mut cmd = quiet_command(self.launcher);
+        cmd.add_args(["rm", "--force", &self.name]);
+        let _ = cmd.run();
+        let _ = self.child.wait();
+    }
+}
+
+/// Start a detached container that stops itself once no build has
+/// run in it for `idle_timeout`, or reuse it if it is already
+/// running. The container must have been created with
+/// [`CargoCommand::Idle`]. Returns the container's name.
+///
+/// The name is derived from the full run command, so a container is
+/// shared by all builds with the same image, code root, and options.
+///
+/// [`CargoCommand::Idle`]: crate::CargoCommand::Idle
+#[throws]
+pub(crate) fn start_daemon(
+    container: &Container,
+    idle_timeout: Duration,
+) -> String {
+    let mut opt = container.run_opt()?;
+    opt.detach = true;
+    opt.env.push((
+        "IDLE_TIMEOUT".into(),
+        idle_timeout.as_secs().max(1).to_string().into(),
+    ));
+
+    let hash = sha2::Sha256::digest(
+        container
+            .launcher
+            .run(opt.clone())
+            .command_line_lossy()
+            .as_bytes(),
+    );
+    let name = format!("{}{:.16x}", DAEMON_PREFIX, hash);
+
+    if is_running(container.launcher, &name)? {
+        info!("reusing container {}", name);
+    } else {
+        // Remove any stopped container left over with the same name
+        let mut rm_cmd = quiet_command(container.launcher);
+        rm_cmd.add_args(["rm", "--force", &name]);
+        rm_cmd.run()?;
+
+        opt.name = Some(name.clone());
+        let mut cmd = container.launcher.run(opt);
+        set_up_command(&mut cmd);
+        cmd.run()?;
+        wait_until_running(container.launcher, &name, None)?;
+    }
+    name
+}
+
+/// Build `bin` in the detached container started by [`start_daemon`],
+/// starting it first if needed.
+///
+/// The container can stop on its own between checking that it's
+/// running and running the build with `exec`, if its idle timeout
+/// expires in between. In that case a fresh container is started and
+/// the build is retried once.
+#[throws]
+pub(crate) fn build_in_daemon(
+    container: &Container,
+    idle_timeout: Duration,
+    bin: &str,
+) {
+    let name = start_daemon(container, idle_timeout)?;
+    if let Err(err) = exec_build(container, &name, bin) {
+        if is_running(container.launcher, &name)? {
+            throw!(err);
+        }
+        info!("container {} stopped before the build, restarting it", name);
+        let name = start_daemon(container, idle_timeout)?;
+        exec_build(container, &name, bin)?;
+    }
+}
+
+/// Stop all the build containers that were kept running by
+/// [`Builder::reuse_container`]. Returns the names of the stopped
+/// containers.
+///
+/// [`Builder::reuse_container`]: crate::Builder::reuse_container
+#[throws]
+pub fn stop_reused_containers(launcher: &Launcher) -> Vec<String> {
+    let mut ps_cmd = launcher.base_command().clone();
+    ps_cmd.add_args(["ps", "--all", "--format", "{{.Names}}"]);
+    ps_cmd.add_arg_pair("--filter", format!("name={}", DAEMON_PREFIX));
+    ps_cmd.log_command = false;
+    ps_cmd.capture = true;
+    let output = ps_cmd.run()?;
+
+    let names: Vec<String> = output
+        .stdout_string_lossy()
+        .lines()
+        .map(str::trim)
+        .filter(|name| name.starts_with(DAEMON_PREFIX))
+        .map(Into::into)
+        .collect();
+    if !names.is_empty() {
+        let mut rm_cmd = launcher.base_command().clone();
+        rm_cmd.add_args(["rm", "--force"]);
+        rm_cmd.add_args(&names);
+        set_up_command(&mut rm_cmd);
+        rm_cmd.run()?;
+    }
+    names
+}
@@ -0,0 +1,125 @@
+//! Support for rebuilding when the source changes.
+
+use crate::code_root::CARGO_CONFIG_FILES;
+use anyhow::Error;
+use fehler::throws;
+use fs_err as fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Modification times of the files beneath a directory.
+pub(crate) type Snapshot = Vec<(PathBuf, SystemTime)>;
+
+/// Convert a "not found" error to `None`. Files can be deleted while
+/// a snapshot is being taken, e.g. an editor's temporary files.
+fn ignore_not_found<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Get the modification time of every file in `paths`, recursing into
+/// directories. Hidden directories (such as `.git`) and `target`
+/// directories are skipped so that build outputs don't trigger
+/// another build, as are the paths in `exclude`, except that cargo
+/// config files in `.cargo` directories are included. Files that are
+/// deleted while the snapshot is taken are skipped.
+#[throws]
+pub(crate) fn snapshot(paths: &[PathBuf], exclude: &[&Path]) -> Snapshot {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for path in paths {
+        let metadata = match ignore_not_found(fs::metadata(path))? {
+            Some(metadata) => metadata,
+            None => continue,
+        };
+        if metadata.is_dir() {
+            dirs.push(path.clone());
+        } else {
+            files.push((path.clone(), metadata.modified()?));
+        }
+    }
+    while let Some(dir) = dirs.pop() {
+        let entries = match ignore_not_found(fs::read_dir(&dir))? {
+            Some(entries) => entries,
+            None => continue,
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if exclude.contains(&path.as_path()) {
+                continue;
+            }
+            let metadata = match ignore_not_found(fs::symlink_metadata(&path))?
+            {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            if metadata.is_dir() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name == ".cargo" {
+                    for config in CARGO_CONFIG_FILES {
+                        let path = path.join(config);
+                        if let Some(metadata) =
+                            ignore_not_found(fs::metadata(&path))?
+                        {
+                            files.push((path, metadata.modified()?));
+                        }
+                    }
+                } else if !name.starts_with('.') && name != "target" {
+                    dirs.push(path);
+                }
+            } else {
+                files.push((path, metadata.modified()?));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("target/release")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join(".cargo")).unwrap();
+        fs::write(root.join(".cargo/config.toml"), "").unwrap();
+        fs::write(root.join(".cargo/credentials.toml"), "").unwrap();
+        fs::write(root.join("Cargo.toml"), "").unwrap();
+        fs::write(root.join("src/main.rs"), "").unwrap();
+        fs::write(root.join("target/release/app"), "").unwrap();
+        fs::write(root.join(".git/HEAD"), "").unwrap();
+
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::write(root.join("out/app"), "").unwrap();
+
+        // A path that doesn't exist is skipped
+        let paths: Vec<_> = snapshot(
+            &[root.to_path_buf(), root.join("missing")],
+            &[&root.join("out")],
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(path, _)| path.strip_prefix(root).unwrap().to_path_buf())
+        .collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new(".cargo/config.toml"),
+                Path::new("Cargo.toml"),
+                Path::new("src/main.rs")
+            ]
+        );
+    }
+}
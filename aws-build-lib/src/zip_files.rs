@@ -0,0 +1,305 @@
+//! Options for the contents of the Lambda zip file.
+
+use anyhow::{anyhow, Context, Error};
+use fehler::{throw, throws};
+use fs_err as fs;
+use std::collections::BTreeMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+/// Name of the executable in the Lambda zip file.
+pub(crate) const BOOTSTRAP: &str = "bootstrap";
+
+/// Highest compression level that deflate accepts.
+const MAX_DEFLATE_LEVEL: u32 = 9;
+
+/// How files are compressed in the Lambda zip file.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ZipCompression {
+    /// Store files without compression. The zip is larger but Lambda
+    /// doesn't have to decompress it.
+    Stored,
+
+    /// Compress files with deflate.
+    #[default]
+    Deflated,
+}
+
+impl ZipCompression {
+    /// Get the options for compressing files with `level`, if set.
+    /// Only deflate has levels, from 0 (fastest) to 9 (smallest).
+    #[throws]
+    pub(crate) fn file_options(&self, level: Option<u32>) -> FileOptions {
+        let method = match self {
+            ZipCompression::Stored => CompressionMethod::Stored,
+            ZipCompression::Deflated => CompressionMethod::Deflated,
+        };
+        if let Some(level) = level {
+            if *self != ZipCompression::Deflated {
+                throw!(anyhow!("zip compression level requires deflated"));
+            }
+            if level > MAX_DEFLATE_LEVEL {
+                throw!(anyhow!(
+                    "invalid zip compression level {}, expected 0 to {}",
+                    level,
+                    MAX_DEFLATE_LEVEL
+                ));
+            }
+        }
+        FileOptions::default()
+            .compression_method(method)
+            .compression_level(level.map(|level| level as i32))
+    }
+}
+
+impl FromStr for ZipCompression {
+    type Err = Error;
+
+    #[throws]
+    fn from_str(s: &str) -> Self {
+        match s {
+            "stored" => ZipCompression::Stored,
+            "deflated" => ZipCompression::Deflated,
+            _ => throw!(anyhow!(
+                "invalid zip compression {}, expected stored or deflated",
+                s
+            )),
+        }
+    }
+}
+
+/// File or directory to add to the Lambda zip file alongside the
+/// executable.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ExtraZipFile {
+    /// Path of the file or directory on the host. Directories are
+    /// added recursively.
+    pub src: PathBuf,
+
+    /// Relative path in the zip file, e.g. `config/app.toml`.
+    pub dst: PathBuf,
+}
+
+impl FromStr for ExtraZipFile {
+    type Err = Error;
+
+    /// Parse `SRC:DST`, or just `SRC` to add it at the top level of
+    /// the zip file under its own name.
+    #[throws]
+    fn from_str(s: &str) -> Self {
+        let (src, dst) = if let Some((src, dst)) = s.split_once(':') {
+            (PathBuf::from(src), PathBuf::from(dst))
+        } else {
+            let src = PathBuf::from(s);
+            let name = src
+                .file_name()
+                .ok_or_else(|| anyhow!("invalid zip file path: {}", s))?;
+            (src.clone(), name.into())
+        };
+        ExtraZipFile { src, dst }
+    }
+}
+
+/// Convert a relative path to a zip entry name, which always uses `/`
+/// as the separator.
+#[throws]
+fn entry_name(path: &Path) -> String {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| anyhow!("non-UTF-8 path in zip file"))?,
+            ),
+            Component::CurDir => {}
+            _ => throw!(anyhow!(
+                "zip file path {} must be relative and within the zip",
+                path.display()
+            )),
+        }
+    }
+    if parts.is_empty() {
+        throw!(anyhow!("zip file path is empty"));
+    }
+    parts.join("/")
+}
+
+/// Get the permissions of a file in the zip: executable if any
+/// execute bit is set on the host, otherwise read-only for others.
+fn entry_permissions(mode: u32) -> u32 {
+    if mode & 0o111 != 0 {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+/// File to write to the zip, read from `src` on the host.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct ZipEntry {
+    pub(crate) src: PathBuf,
+    pub(crate) permissions: u32,
+}
+
+/// Expand `files` into the individual files to write to the zip,
+/// keyed by entry name. Directories are walked recursively and
+/// symlinks are followed. It's an error for two files to have the
+/// same name, for any file to be named `bootstrap`, or for a symlink
+/// to point to a directory that contains it.
+#[throws]
+pub(crate) fn collect_entries(
+    files: &[ExtraZipFile],
+) -> BTreeMap<String, ZipEntry> {
+    let mut entries = BTreeMap::new();
+    for file in files {
+        // Each directory is visited along with the device and inode
+        // of the directories above it, to detect symlink loops
+        let mut to_visit =
+            vec![(file.src.clone(), file.dst.clone(), Vec::new())];
+        while let Some((src, dst, ancestors)) = to_visit.pop() {
+            let metadata = fs::metadata(&src)?;
+            if metadata.is_dir() {
+                let id = (metadata.dev(), metadata.ino());
+                if ancestors.contains(&id) {
+                    throw!(anyhow!(
+                        "{} is a symlink to a directory that contains it",
+                        src.display()
+                    ));
+                }
+                let mut ancestors = ancestors;
+                ancestors.push(id);
+                for entry in fs::read_dir(&src)? {
+                    let entry = entry?;
+                    to_visit.push((
+                        entry.path(),
+                        dst.join(entry.file_name()),
+                        ancestors.clone(),
+                    ));
+                }
+                continue;
+            }
+
+            let name = entry_name(&dst).with_context(|| {
+                format!("invalid zip path for {}", src.display())
+            })?;
+            if name == BOOTSTRAP {
+                throw!(anyhow!(
+                    "{} would replace the executable in the zip",
+                    src.display()
+                ));
+            }
+            let entry = ZipEntry {
+                src,
+                permissions: entry_permissions(metadata.permissions().mode()),
+            };
+            if let Some(existing) = entries.insert(name.clone(), entry) {
+                throw!(anyhow!(
+                    "{} is added to the zip more than once, from {}",
+                    name,
+                    existing.src.display()
+                ));
+            }
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_options() {
+        let deflated = ZipCompression::Deflated;
+        assert!(deflated.file_options(None).is_ok());
+        assert!(deflated.file_options(Some(0)).is_ok());
+        assert!(deflated.file_options(Some(9)).is_ok());
+        assert!(deflated.file_options(Some(10)).is_err());
+        assert!(ZipCompression::Stored.file_options(None).is_ok());
+        assert!(ZipCompression::Stored.file_options(Some(1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_extra_zip_file() {
+        assert_eq!(
+            "certs/ca.pem".parse::<ExtraZipFile>().unwrap(),
+            ExtraZipFile {
+                src: "certs/ca.pem".into(),
+                dst: "ca.pem".into(),
+            }
+        );
+        assert_eq!(
+            "/etc/app:config".parse::<ExtraZipFile>().unwrap(),
+            ExtraZipFile {
+                src: "/etc/app".into(),
+                dst: "config".into(),
+            }
+        );
+        assert!("/".parse::<ExtraZipFile>().is_err());
+    }
+
+    #[test]
+    fn test_collect_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("templates/email")).unwrap();
+        fs::write(root.join("templates/index.html"), "").unwrap();
+        fs::write(root.join("templates/email/welcome.txt"), "").unwrap();
+        fs::write(root.join("run.sh"), "").unwrap();
+        fs::set_permissions(
+            root.join("run.sh"),
+            std::fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+
+        let file = |src: &str, dst: &str| ExtraZipFile {
+            src: root.join(src),
+            dst: dst.into(),
+        };
+        let entries = collect_entries(&[
+            file("templates", "./tmpl"),
+            file("run.sh", "bin/run.sh"),
+        ])
+        .unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|(name, entry)| (name.as_str(), entry.permissions))
+                .collect::<Vec<_>>(),
+            [
+                ("bin/run.sh", 0o755),
+                ("tmpl/email/welcome.txt", 0o644),
+                ("tmpl/index.html", 0o644),
+            ]
+        );
+
+        for files in [
+            vec![file("run.sh", "bootstrap")],
+            vec![file("run.sh", "../run.sh")],
+            vec![file("run.sh", "/run.sh")],
+            vec![file("run.sh", "a"), file("templates/index.html", "a")],
+            vec![file("missing", "missing")],
+        ] {
+            assert!(collect_entries(&files).is_err(), "{:?}", files);
+        }
+
+        // A symlink to a sibling directory is followed, but a symlink
+        // to an ancestor is an error rather than an endless walk
+        std::os::unix::fs::symlink(
+            root.join("templates/email"),
+            root.join("templates/mail"),
+        )
+        .unwrap();
+        let entries = collect_entries(&[file("templates", "tmpl")]).unwrap();
+        assert!(entries.contains_key("tmpl/mail/welcome.txt"));
+        std::os::unix::fs::symlink(
+            root.join("templates"),
+            root.join("templates/email/loop"),
+        )
+        .unwrap();
+        assert!(collect_entries(&[file("templates", "tmpl")]).is_err());
+    }
+}
@@ -2,10 +2,19 @@ use anyhow::{anyhow, Error};
 use argh::FromArgs;
 use aws_build_lib::docker_command::command_run::Command;
 use aws_build_lib::docker_command::Launcher;
-use aws_build_lib::{BuildMode, Builder, DEFAULT_RUST_VERSION};
-use fehler::throws;
+use aws_build_lib::runtime_emulator::InvocationResult;
+use aws_build_lib::{
+    default_output_dir, default_symlink_path, stop_reused_containers,
+    BuildMode, Builder, ExtraZipFile, Invoker, InvokerOutput, LambdaCode,
+    LambdaDeployer, NameTemplate, Retention, RpmOptions, S3Publisher,
+    SmokeRunner, Symlink, TemplateKind, ZipCompression, DEFAULT_RUST_VERSION,
+};
+use fehler::{throw, throws};
+use fs_err as fs;
 use std::env;
+use std::io::{self, Read};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use log::{Level, Metadata, Record};
 
@@ -27,17 +36,85 @@ impl log::Log for Logger {
 
 static LOGGER: Logger = Logger;
 
+/// Seconds to wait for a locally invoked function to respond.
+const DEFAULT_INVOKE_TIMEOUT: u64 = 30;
+
 #[throws(String)]
 fn parse_command(s: &str) -> Command {
     Command::from_whitespace_separated_str(s)
         .ok_or_else(|| "command is empty".to_string())?
 }
 
+/// Parse a `KEY=VALUE` environment variable.
+#[throws(String)]
+fn parse_env_var(s: &str) -> (String, String) {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid environment variable: {}", s))?;
+    if key.is_empty() {
+        throw!(format!("invalid environment variable: {}", s));
+    }
+    (key.into(), value.into())
+}
+
+/// Parse a size in bytes, optionally with a K, M, or G suffix for
+/// powers of 1024.
+#[throws(String)]
+fn parse_size(s: &str) -> u64 {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&s[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&s[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&s[..index], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size: {}", s))?
+}
+
+/// Parse the contents of an env file. Each line is a `KEY=VALUE`
+/// pair. Empty lines and lines starting with `#` are ignored. No
+/// quoting or escaping is done.
+#[throws]
+fn parse_env_file(contents: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        vars.push(parse_env_var(line).map_err(Error::msg)?);
+    }
+    vars
+}
+
 #[derive(Debug, FromArgs)]
 #[argh(description = "Build the project in a container for deployment to AWS.
 
 mode: al2 or lambda (for Amazon Linux 2 or AWS Lambda, respectively)
 project: path of the project to build (default: current directory)
+
+Commands:
+  aws-build test <mode> [<project>] [options]
+    run the project's tests in the build container, using the same
+    options as a build
+  aws-build watch <mode> [<project>] [options]
+    rebuild whenever a file in the code root changes, using the same
+    options as a build
+  aws-build deploy lambda [<project>] --function <function> [options]
+    build, then update the Lambda function's code, using the same
+    options as a build
+  aws-build prune <mode> [<project>] [--keep-last <n>] [--keep-days <n>]
+    delete old outputs of previous builds
+  aws-build stop [--container-cmd <container-cmd>]
+    stop the build containers kept running by --reuse-container
+  aws-build invoke [<project>] [--event <event>]
+    run the latest Lambda build locally with the given event
+  aws-build run al2 [<project>] [options] -- [<args>]
+    run the latest Amazon Linux 2 build in a clean amazonlinux:2
+    container
 ")]
 struct Opt {
     /// base container command, e.g. docker or podman, auto-detected by
@@ -53,6 +130,10 @@ struct Opt {
     #[argh(switch)]
     strip: bool,
 
+    /// move debug info to a separate .debug file next to the output
+    #[argh(switch)]
+    split_debug_info: bool,
+
     /// name of the binary target to build (required if there is more
     /// than one binary target)
     #[argh(option)]
@@ -63,10 +144,195 @@ struct Opt {
     package: Vec<String>,
 
     /// root directory to mount into the container, must contain the
-    /// project path (default: project path)
+    /// project path and path dependencies (default: detected from the
+    /// workspace and its path dependencies)
     #[argh(option)]
     code_root: Option<PathBuf>,
 
+    /// only mount the workspace members, path dependencies, Cargo.toml,
+    /// Cargo.lock, and the workspace's .cargo directory and vendored
+    /// sources rather than the whole code root; files outside those,
+    /// e.g. a README included from a parent directory, are not visible
+    /// to the build
+    #[argh(switch)]
+    minimal_mounts: bool,
+
+    /// cargo config file to apply to the build in the container, e.g.
+    /// ~/.cargo/config.toml (can be repeated)
+    #[argh(option)]
+    cargo_config: Vec<PathBuf>,
+
+    /// environment variable to set in the build container, in the
+    /// form KEY=VALUE (can be repeated)
+    #[argh(option, from_str_fn(parse_env_var))]
+    env: Vec<(String, String)>,
+
+    /// file of KEY=VALUE lines to set as environment variables in the
+    /// build container
+    #[argh(option)]
+    env_file: Option<PathBuf>,
+
+    /// flags to pass to rustc via RUSTFLAGS
+    #[argh(option)]
+    rustflags: Option<String>,
+
+    /// CPU to generate code for (-C target-cpu), e.g. neoverse-n1
+    #[argh(option)]
+    target_cpu: Option<String>,
+
+    /// base image to use instead of the default Amazon Linux 2 or
+    /// Lambda build image
+    #[argh(option)]
+    base_image: Option<String>,
+
+    /// file of Dockerfile instructions to add to the build image (can
+    /// be repeated)
+    #[argh(option)]
+    dockerfile_snippet: Vec<PathBuf>,
+
+    /// executable script to run in the container before the build
+    #[argh(option)]
+    pre_build_hook: Option<PathBuf>,
+
+    /// executable script to run in the container after the build
+    #[argh(option)]
+    post_build_hook: Option<PathBuf>,
+
+    /// keep the build container running and reuse it for later builds
+    /// with the same options
+    #[argh(switch)]
+    reuse_container: bool,
+
+    /// seconds without a build after which a reused build container
+    /// stops itself (default: 900)
+    #[argh(option, default = "900")]
+    idle_timeout: u64,
+
+    /// after the build, delete old outputs except the newest n of each
+    /// binary (and any kept by --keep-days)
+    #[argh(option)]
+    keep_last: Option<usize>,
+
+    /// after the build, delete old outputs except those built in the
+    /// last n days (and any kept by --keep-last)
+    #[argh(option)]
+    keep_days: Option<u32>,
+
+    /// template for output file names, using placeholders {{mode}},
+    /// {{arch}}, {{bin}}, {{version}}, {{git_sha}}, {{hash}}, {{date}},
+    /// {{time}}, and {{profile}}; {{hash}} is required (default:
+    /// {{mode}}-{{bin}}-{{date}}-{{hash}})
+    #[argh(option, default = "NameTemplate::default()")]
+    name_template: NameTemplate,
+
+    /// directory to write outputs and build caches to (default:
+    /// aws-build in the target directory)
+    #[argh(option)]
+    output_dir: Option<PathBuf>,
+
+    /// path of the symlink to the latest output (default:
+    /// `latest-<mode>` in the target directory)
+    #[argh(option)]
+    symlink: Option<PathBuf>,
+
+    /// don't create a symlink to the latest output
+    #[argh(switch)]
+    no_symlink: bool,
+
+    /// fail the build if the output (the zip file for Lambda) is larger
+    /// than this, in bytes or with a K, M, or G suffix
+    #[argh(option, from_str_fn(parse_size))]
+    size_budget: Option<u64>,
+
+    /// compression method for the Lambda zip file, stored or deflated
+    /// (default: deflated)
+    #[argh(option)]
+    zip_compression: Option<ZipCompression>,
+
+    /// deflate compression level of the Lambda zip file, from 0
+    /// (fastest) to 9 (smallest)
+    #[argh(option)]
+    zip_compression_level: Option<u32>,
+
+    /// file or directory to add to the Lambda zip file, as src or
+    /// src:path-in-zip (can be repeated)
+    #[argh(option)]
+    zip_file: Vec<ExtraZipFile>,
+
+    /// al2 only: also package the binary as an RPM
+    #[argh(switch)]
+    rpm: bool,
+
+    /// directory the RPM installs the binary to (default: /usr/bin)
+    #[argh(option)]
+    rpm_install_dir: Option<PathBuf>,
+
+    /// systemd unit file for the RPM to install
+    #[argh(option)]
+    rpm_systemd_unit: Option<PathBuf>,
+
+    /// config file for the RPM to install to `/etc/<package name>` (can
+    /// be repeated)
+    #[argh(option)]
+    rpm_config_file: Vec<PathBuf>,
+
+    /// release of the RPM (default: the build's UTC time as
+    /// YYYYMMDDHHMMSS, so that rebuilds of a version are upgrades)
+    #[argh(option)]
+    rpm_release: Option<String>,
+
+    /// al2 only: also package the binary as a CodeDeploy bundle,
+    /// configured by [package.metadata.aws-build]
+    #[argh(switch)]
+    codedeploy: bool,
+
+    /// lambda only: also write a template snippet for the function,
+    /// sam or cloudformation
+    #[argh(option)]
+    template: Option<TemplateKind>,
+
+    /// after the build, upload the output, any debug info file, RPM,
+    /// and CodeDeploy bundle, and a manifest to this S3 bucket using
+    /// the aws CLI
+    #[argh(option)]
+    s3_bucket: Option<String>,
+
+    /// prefix of the uploaded S3 keys, e.g. builds/
+    #[argh(option)]
+    s3_prefix: Option<String>,
+
+    /// URL of an S3-compatible service to upload to instead of AWS
+    #[argh(option)]
+    s3_endpoint_url: Option<String>,
+
+    /// deploy only: name or ARN of the Lambda function to update
+    #[argh(option)]
+    function: Option<String>,
+
+    /// deploy only: publish a new version of the function
+    #[argh(switch)]
+    publish_version: bool,
+
+    /// deploy only: alias to point at the new version, created if
+    /// needed (implies --publish-version)
+    #[argh(option)]
+    alias: Option<String>,
+
+    /// deploy only: URL of a Lambda-compatible API to use instead of
+    /// AWS
+    #[argh(option)]
+    lambda_endpoint_url: Option<String>,
+
+    /// watch only: file containing an event to invoke the Lambda
+    /// function with after each build
+    #[argh(option)]
+    invoke_event: Option<PathBuf>,
+
+    /// watch only: seconds to wait for the function to respond to
+    /// --invoke-event (default: 30)
+    #[argh(option)]
+    invoke_timeout: Option<u64>,
+
     /// whether to build for Amazon Linux 2 or AWS Lambda
     #[argh(positional)]
     mode: BuildMode,
@@ -76,38 +342,558 @@ struct Opt {
     project: PathBuf,
 }
 
+#[throws]
+fn make_launcher(container_cmd: Option<Command>) -> Launcher {
+    if let Some(cmd) = container_cmd {
+        Launcher::new(cmd)
+    } else {
+        Launcher::auto()
+            .ok_or_else(|| anyhow!("no container system detected"))?
+    }
+}
+
 impl Opt {
+    /// Check that options that only apply to one command aren't
+    /// passed to another, where they would be silently ignored.
+    /// `command` is the subcommand name, or `None` for a plain build.
     #[throws]
-    fn launcher(&self) -> Launcher {
-        if let Some(cmd) = self.container_cmd.as_ref() {
-            Launcher::new(cmd.clone())
-        } else {
-            Launcher::auto()
-                .ok_or_else(|| anyhow!("no container system detected"))?
+    fn check_command_options(&self, command: Option<&str>) {
+        if self.symlink.is_some() && self.no_symlink {
+            throw!(anyhow!(
+                "--symlink and --no-symlink can't be used together"
+            ));
+        }
+        if command.is_some() && command != Some("deploy") {
+            // Outputs are only uploaded by a plain build and deploy
+            let s3_options = [
+                ("--s3-bucket", self.s3_bucket.is_some()),
+                ("--s3-prefix", self.s3_prefix.is_some()),
+                ("--s3-endpoint-url", self.s3_endpoint_url.is_some()),
+            ];
+            for (name, is_set) in s3_options {
+                if is_set {
+                    throw!(anyhow!(
+                        "{} can't be used with the {} command",
+                        name,
+                        command.unwrap_or_default()
+                    ));
+                }
+            }
+        }
+        if command == Some("test") {
+            // Tests don't write an output
+            let output_options = [
+                ("--keep-last", self.keep_last.is_some()),
+                ("--keep-days", self.keep_days.is_some()),
+                ("--size-budget", self.size_budget.is_some()),
+                ("--zip-compression", self.zip_compression.is_some()),
+                (
+                    "--zip-compression-level",
+                    self.zip_compression_level.is_some(),
+                ),
+                ("--zip-file", !self.zip_file.is_empty()),
+                ("--rpm", self.rpm),
+                ("--rpm-install-dir", self.rpm_install_dir.is_some()),
+                ("--rpm-systemd-unit", self.rpm_systemd_unit.is_some()),
+                ("--rpm-config-file", !self.rpm_config_file.is_empty()),
+                ("--rpm-release", self.rpm_release.is_some()),
+                ("--codedeploy", self.codedeploy),
+                ("--template", self.template.is_some()),
+            ];
+            for (name, is_set) in output_options {
+                if is_set {
+                    throw!(anyhow!(
+                        "{} can't be used with the test command",
+                        name
+                    ));
+                }
+            }
+        }
+        if command != Some("deploy") {
+            let deploy_options = [
+                ("--function", self.function.is_some()),
+                ("--publish-version", self.publish_version),
+                ("--alias", self.alias.is_some()),
+                ("--lambda-endpoint-url", self.lambda_endpoint_url.is_some()),
+            ];
+            for (name, is_set) in deploy_options {
+                if is_set {
+                    throw!(anyhow!(
+                        "{} can only be used with the deploy command",
+                        name
+                    ));
+                }
+            }
+        }
+        if command != Some("watch") {
+            let watch_options = [
+                ("--invoke-event", self.invoke_event.is_some()),
+                ("--invoke-timeout", self.invoke_timeout.is_some()),
+            ];
+            for (name, is_set) in watch_options {
+                if is_set {
+                    throw!(anyhow!(
+                        "{} can only be used with the watch command",
+                        name
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Get the publisher for uploading the output, if enabled.
+    fn publisher(&self) -> Option<S3Publisher> {
+        self.s3_bucket.as_ref().map(|bucket| S3Publisher {
+            bucket: bucket.clone(),
+            prefix: self.s3_prefix.clone().unwrap_or_default(),
+            endpoint_url: self.s3_endpoint_url.clone(),
+            aws_program: "aws".into(),
+        })
+    }
+
+    /// Convert the options into a [`Builder`].
+    #[throws]
+    fn into_builder(self) -> Builder {
+        let launcher = make_launcher(self.container_cmd)?;
+
+        // Variables set on the command line take precedence over the
+        // env file.
+        let mut env = Vec::new();
+        if let Some(path) = &self.env_file {
+            env = parse_env_file(&fs::read_to_string(path)?)?;
+        }
+        env.extend(self.env);
+
+        let dockerfile_snippets = self
+            .dockerfile_snippet
+            .iter()
+            .map(fs::read_to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Builder {
+            rust_version: self.rust_version,
+            mode: self.mode,
+            bin: self.bin,
+            strip: self.strip,
+            split_debug_info: self.split_debug_info,
+            launcher,
+            code_root: self.code_root,
+            minimal_mounts: self.minimal_mounts,
+            size_budget: self.size_budget,
+            zip_compression: self.zip_compression.unwrap_or_default(),
+            zip_compression_level: self.zip_compression_level,
+            extra_zip_files: self.zip_file,
+            codedeploy: self.codedeploy,
+            template: self.template,
+            rpm: if self.rpm {
+                let defaults = RpmOptions::default();
+                Some(RpmOptions {
+                    install_dir: self
+                        .rpm_install_dir
+                        .unwrap_or(defaults.install_dir),
+                    systemd_unit: self.rpm_systemd_unit,
+                    config_files: self.rpm_config_file,
+                    release: self.rpm_release,
+                })
+            } else {
+                None
+            },
+            project_path: self.project,
+            packages: self.package,
+            relabel: None,
+            cargo_config: self.cargo_config,
+            env,
+            rustflags: self.rustflags,
+            target_cpu: self.target_cpu,
+            base_image: self.base_image,
+            dockerfile_snippets,
+            pre_build_hook: self.pre_build_hook,
+            post_build_hook: self.post_build_hook,
+            reuse_container: if self.reuse_container {
+                Some(Duration::from_secs(self.idle_timeout))
+            } else {
+                None
+            },
+            retention: Retention {
+                keep_last: self.keep_last,
+                keep_days: self.keep_days,
+            },
+            name_template: self.name_template,
+            output_dir: self.output_dir,
+            symlink: if self.no_symlink {
+                Symlink::Disabled
+            } else if let Some(path) = self.symlink {
+                Symlink::Custom(path)
+            } else {
+                Symlink::Default
+            },
         }
     }
 }
 
+/// Delete old outputs of previous builds. An output is kept if either
+/// --keep-last or --keep-days keeps it. The output that the latest-*
+/// symlink points to is never deleted.
+#[derive(Debug, FromArgs)]
+struct PruneOpt {
+    /// keep the newest n outputs of each binary
+    #[argh(option)]
+    keep_last: Option<usize>,
+
+    /// keep outputs built in the last n days
+    #[argh(option)]
+    keep_days: Option<u32>,
+
+    /// template that output file names were created with (default:
+    /// {{mode}}-{{bin}}-{{date}}-{{hash}})
+    #[argh(option, default = "NameTemplate::default()")]
+    name_template: NameTemplate,
+
+    /// directory that outputs were written to (default: aws-build in
+    /// the target directory)
+    #[argh(option)]
+    output_dir: Option<PathBuf>,
+
+    /// symlink to the latest output, which is never deleted (default:
+    /// `latest-<mode>` in the target directory)
+    #[argh(option)]
+    symlink: Option<PathBuf>,
+
+    /// build mode of the outputs to prune, al2 or lambda
+    #[argh(positional)]
+    mode: BuildMode,
+
+    /// path of the project (default: current directory)
+    #[argh(positional, default = "env::current_dir().unwrap()")]
+    project: PathBuf,
+}
+
+#[throws]
+fn prune(opt: PruneOpt) {
+    if opt.keep_last.is_none() && opt.keep_days.is_none() {
+        throw!(anyhow!(
+            "at least one of --keep-last or --keep-days is required"
+        ));
+    }
+    let retention = Retention {
+        keep_last: opt.keep_last,
+        keep_days: opt.keep_days,
+    };
+    let output_dir = match opt.output_dir {
+        Some(path) => path,
+        None => default_output_dir(&opt.project)?,
+    };
+    let symlink_path = match opt.symlink {
+        Some(path) => path,
+        None => default_symlink_path(&opt.project, opt.mode)?,
+    };
+    retention.prune(
+        opt.mode,
+        &opt.name_template,
+        &output_dir,
+        Some(&symlink_path),
+    )?;
+}
+
+/// Stop the build containers kept running by --reuse-container.
+#[derive(Debug, FromArgs)]
+struct StopOpt {
+    /// base container command, e.g. docker or podman, auto-detected by
+    /// default
+    #[argh(option, from_str_fn(parse_command))]
+    container_cmd: Option<Command>,
+}
+
+#[throws]
+fn stop(opt: StopOpt) {
+    let launcher = make_launcher(opt.container_cmd)?;
+    for name in stop_reused_containers(&launcher)? {
+        println!("stopped {}", name);
+    }
+}
+
+/// Invoke the latest Lambda build locally. The zip file is run in a
+/// provided.al2 container with an emulated Lambda runtime API.
+#[derive(Debug, FromArgs)]
+struct InvokeOpt {
+    /// base container command, e.g. docker or podman, auto-detected by
+    /// default
+    #[argh(option, from_str_fn(parse_command))]
+    container_cmd: Option<Command>,
+
+    /// file containing the event to send (default: read from stdin)
+    #[argh(option)]
+    event: Option<PathBuf>,
+
+    /// environment variable to set for the function, in the form
+    /// KEY=VALUE (can be repeated)
+    #[argh(option, from_str_fn(parse_env_var))]
+    env: Vec<(String, String)>,
+
+    /// path of the Lambda zip file (default: the latest-lambda symlink
+    /// in the target directory)
+    #[argh(option)]
+    zip: Option<PathBuf>,
+
+    /// seconds to wait for the function to respond (default: 30)
+    #[argh(option, default = "DEFAULT_INVOKE_TIMEOUT")]
+    timeout: u64,
+
+    /// path of the project (default: current directory)
+    #[argh(positional, default = "env::current_dir().unwrap()")]
+    project: PathBuf,
+}
+
+#[throws]
+fn invoke(opt: InvokeOpt) {
+    let event = if let Some(path) = &opt.event {
+        fs::read(path)?
+    } else {
+        let mut event = Vec::new();
+        io::stdin().read_to_end(&mut event)?;
+        event
+    };
+
+    let invoker = Invoker {
+        launcher: make_launcher(opt.container_cmd)?,
+        zip_path: match opt.zip {
+            Some(path) => path,
+            None => default_symlink_path(&opt.project, BuildMode::Lambda)?,
+        },
+        event,
+        env: opt.env,
+        timeout: Duration::from_secs(opt.timeout),
+    };
+    print_invoker_output(invoker.run()?)?;
+}
+
+/// Print the function's logs and result. An error is returned if the
+/// function returned an error.
+#[throws]
+fn print_invoker_output(output: InvokerOutput) {
+    println!("logs:\n{}", output.logs);
+    println!("duration: {:.2?}", output.duration);
+    match output.result {
+        InvocationResult::Response(body) => {
+            println!("response:\n{}", String::from_utf8_lossy(&body));
+        }
+        InvocationResult::Error(payload) => {
+            println!("error:\n{}", String::from_utf8_lossy(&payload.body));
+            throw!(anyhow!(
+                "function returned an error ({})",
+                payload.error_type.as_deref().unwrap_or("unknown type")
+            ));
+        }
+    }
+}
+
+/// Run the latest Amazon Linux 2 build in a clean amazonlinux:2
+/// container, without the Rust toolchain or dev packages. Arguments
+/// after "--" are passed to the binary.
+#[derive(Debug, FromArgs)]
+struct RunBinaryOpt {
+    /// base container command, e.g. docker or podman, auto-detected by
+    /// default
+    #[argh(option, from_str_fn(parse_command))]
+    container_cmd: Option<Command>,
+
+    /// path of the binary (default: the latest-al2 symlink in the
+    /// target directory)
+    #[argh(option)]
+    binary: Option<PathBuf>,
+
+    /// build mode, must be al2
+    #[argh(positional)]
+    mode: BuildMode,
+
+    /// path of the project (default: current directory)
+    #[argh(positional, default = "env::current_dir().unwrap()")]
+    project: PathBuf,
+}
+
+/// Split the arguments of the run command at the first "--" into the
+/// command's own arguments and the arguments for the binary. This is
+/// done before parsing since argh treats everything after "--" as
+/// positional arguments of the command.
+fn split_binary_args(args: &[String]) -> (&[String], Vec<String>) {
+    match args.iter().position(|arg| arg == "--") {
+        Some(index) => (&args[..index], args[index + 1..].to_vec()),
+        None => (args, Vec::new()),
+    }
+}
+
+#[throws]
+fn run_binary(opt: RunBinaryOpt, args: Vec<String>) {
+    if opt.mode != BuildMode::AmazonLinux2 {
+        throw!(anyhow!(
+            "only al2 builds can be run, use `aws-build invoke` for lambda"
+        ));
+    }
+
+    let runner = SmokeRunner {
+        launcher: make_launcher(opt.container_cmd)?,
+        binary_path: match opt.binary {
+            Some(path) => path,
+            None => {
+                default_symlink_path(&opt.project, BuildMode::AmazonLinux2)?
+            }
+        },
+        args,
+    };
+    runner.run()?;
+}
+
+#[throws]
+fn deploy(opt: Opt) {
+    opt.check_command_options(Some("deploy"))?;
+    if opt.mode != BuildMode::Lambda {
+        throw!(anyhow!("only lambda builds can be deployed"));
+    }
+    let deployer = LambdaDeployer {
+        function: opt
+            .function
+            .clone()
+            .ok_or_else(|| anyhow!("--function is required"))?,
+        publish_version: opt.publish_version,
+        alias: opt.alias.clone(),
+        endpoint_url: opt.lambda_endpoint_url.clone(),
+        aws_program: "aws".into(),
+    };
+    let publisher = opt.publisher();
+    let output = opt.into_builder()?.run()?;
+
+    // Deploy from S3 if the zip was uploaded, otherwise send it directly
+    let code = if let Some(publisher) = publisher {
+        publisher.publish(&output)?;
+        LambdaCode::S3 {
+            key: publisher.output_key(&output)?,
+            bucket: publisher.bucket,
+        }
+    } else {
+        LambdaCode::ZipFile(output.real)
+    };
+    deployer.deploy(&code)?;
+}
+
+#[throws]
+fn watch(opt: Opt) {
+    opt.check_command_options(Some("watch"))?;
+    if opt.invoke_event.is_some() && opt.mode != BuildMode::Lambda {
+        throw!(anyhow!("--invoke-event requires lambda mode"));
+    }
+    let invoke_event = opt.invoke_event.clone();
+    let invoke_timeout = Duration::from_secs(
+        opt.invoke_timeout.unwrap_or(DEFAULT_INVOKE_TIMEOUT),
+    );
+    let builder = opt.into_builder()?;
+
+    builder.watch(|output| {
+        if let Some(path) = &invoke_event {
+            // Read the event each time so that it can be edited
+            // while watching.
+            let invoker = Invoker {
+                launcher: builder.launcher.clone(),
+                zip_path: output.real.clone(),
+                event: fs::read(path)?,
+                env: Vec::new(),
+                timeout: invoke_timeout,
+            };
+            print_invoker_output(invoker.run()?)?;
+        }
+        Ok(())
+    })?;
+}
+
+/// Names of the subcommands dispatched by `main`.
+const SUBCOMMANDS: &[&str] =
+    &["test", "watch", "deploy", "prune", "stop", "invoke", "run"];
+
+/// Print the output of an early exit from argh and exit.
+fn exit_early(early_exit: argh::EarlyExit) -> ! {
+    std::process::exit(match early_exit.status {
+        Ok(()) => {
+            println!("{}", early_exit.output);
+            0
+        }
+        Err(()) => {
+            eprintln!("{}", early_exit.output);
+            1
+        }
+    })
+}
+
+/// Parse the arguments following a subcommand name.
+///
+/// This is equivalent to `argh::from_env`. Subcommands are dispatched
+/// by hand rather than with argh so that plain `aws-build <mode>`
+/// continues to work.
+fn parse_subcommand<T: FromArgs>(name: &str, args: &[String]) -> T {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    T::from_args(&["aws-build", name], &args)
+        .unwrap_or_else(|early_exit| exit_early(early_exit))
+}
+
+/// Find a subcommand name that isn't the first argument, e.g. `test`
+/// in `aws-build --strip test al2`.
+fn find_misplaced_subcommand(args: &[String]) -> Option<&str> {
+    args.iter()
+        .skip(1)
+        .map(String::as_str)
+        .find(|arg| SUBCOMMANDS.contains(arg))
+}
+
+/// Parse the arguments of a plain build. Subcommands are only
+/// recognized as the first argument, so if parsing fails because of a
+/// subcommand name after an option, say so rather than reporting an
+/// invalid mode.
+fn parse_build(args: &[String]) -> Opt {
+    let build_args: Vec<&str> = args.iter().map(String::as_str).collect();
+    Opt::from_args(&["aws-build"], &build_args).unwrap_or_else(|early_exit| {
+        if early_exit.status.is_err() {
+            if let Some(name) = find_misplaced_subcommand(args) {
+                eprintln!(
+                    "Error: the {name} command must be the first argument, \
+                     e.g. `aws-build {name} <mode> --strip`",
+                    name = name
+                );
+                std::process::exit(1);
+            }
+        }
+        exit_early(early_exit)
+    })
+}
+
 #[throws]
 fn main() {
     log::set_logger(&LOGGER)
         .map(|()| log::set_max_level(log::LevelFilter::Info))?;
 
-    let opt: Opt = argh::from_env();
-    let launcher = opt.launcher()?;
-
-    let builder = Builder {
-        rust_version: opt.rust_version,
-        mode: opt.mode,
-        bin: opt.bin,
-        strip: opt.strip,
-        launcher,
-        code_root: opt.code_root.unwrap_or_else(|| opt.project.clone()),
-        project_path: opt.project,
-        packages: opt.package,
-        relabel: None,
-    };
-    builder.run()?;
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("test") => {
+            let opt: Opt = parse_subcommand("test", &args[2..]);
+            opt.check_command_options(Some("test"))?;
+            opt.into_builder()?.test()?;
+        }
+        Some("watch") => watch(parse_subcommand("watch", &args[2..]))?,
+        Some("deploy") => deploy(parse_subcommand("deploy", &args[2..]))?,
+        Some("prune") => prune(parse_subcommand("prune", &args[2..]))?,
+        Some("stop") => stop(parse_subcommand("stop", &args[2..]))?,
+        Some("invoke") => invoke(parse_subcommand("invoke", &args[2..]))?,
+        Some("run") => {
+            let (run_args, binary_args) = split_binary_args(&args[2..]);
+            run_binary(parse_subcommand("run", run_args), binary_args)?;
+        }
+        _ => {
+            let opt = parse_build(&args[1..]);
+            opt.check_command_options(None)?;
+            let publisher = opt.publisher();
+            let output = opt.into_builder()?.run()?;
+            if let Some(publisher) = publisher {
+                publisher.publish(&output)?;
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -126,4 +912,146 @@ mod tests {
         usage = usage.replace("Usage: ", "");
         assert!(readme.contains(&usage));
     }
+
+    #[test]
+    fn test_find_misplaced_subcommand() {
+        let args = |args: &[&str]| -> Vec<String> {
+            args.iter().map(|arg| arg.to_string()).collect()
+        };
+        assert_eq!(
+            find_misplaced_subcommand(&args(&[
+                "--container-cmd",
+                "podman",
+                "test",
+                "al2"
+            ])),
+            Some("test")
+        );
+        assert_eq!(find_misplaced_subcommand(&args(&["al2", "--strip"])), None);
+    }
+
+    #[test]
+    fn test_split_binary_args() {
+        let args = |args: &[&str]| -> Vec<String> {
+            args.iter().map(|arg| arg.to_string()).collect()
+        };
+        let run_args = args(&["al2", "proj", "--", "--port", "--", "1"]);
+        let (own, binary) = split_binary_args(&run_args);
+        assert_eq!(own, args(&["al2", "proj"]));
+        assert_eq!(binary, args(&["--port", "--", "1"]));
+
+        let run_args = args(&["al2"]);
+        assert_eq!(split_binary_args(&run_args), (&run_args[..], Vec::new()));
+    }
+
+    #[test]
+    fn test_check_command_options() {
+        let opt = Opt::from_args(
+            &["aws-build"],
+            &["lambda", "--invoke-event", "event.json"],
+        )
+        .unwrap();
+        assert!(opt.check_command_options(Some("watch")).is_ok());
+        assert!(opt.check_command_options(Some("test")).is_err());
+        assert!(opt.check_command_options(None).is_err());
+
+        let opt = Opt::from_args(
+            &["aws-build"],
+            &["lambda", "--invoke-timeout", "60"],
+        )
+        .unwrap();
+        assert!(opt.check_command_options(Some("watch")).is_ok());
+        assert!(opt.check_command_options(None).is_err());
+
+        for args in [
+            ["--function", "my-func"].as_slice(),
+            &["--publish-version"],
+            &["--alias", "live"],
+            &["--lambda-endpoint-url", "http://localhost:4566"],
+        ] {
+            let opt =
+                Opt::from_args(&["aws-build"], &[&["lambda"], args].concat())
+                    .unwrap();
+            assert!(opt.check_command_options(Some("deploy")).is_ok());
+            assert!(opt.check_command_options(Some("watch")).is_err());
+            assert!(opt.check_command_options(None).is_err(), "{:?}", args);
+        }
+
+        let opt = Opt::from_args(
+            &["aws-build"],
+            &["lambda", "--symlink", "latest", "--no-symlink"],
+        )
+        .unwrap();
+        assert!(opt.check_command_options(None).is_err());
+
+        for args in [
+            ["--s3-bucket", "bucket"].as_slice(),
+            &["--s3-prefix", "builds/"],
+            &["--s3-endpoint-url", "http://localhost:9000"],
+        ] {
+            let opt =
+                Opt::from_args(&["aws-build"], &[&["lambda"], args].concat())
+                    .unwrap();
+            assert!(opt.check_command_options(None).is_ok());
+            assert!(opt.check_command_options(Some("deploy")).is_ok());
+            assert!(opt.check_command_options(Some("watch")).is_err());
+            assert!(opt.check_command_options(Some("test")).is_err());
+        }
+
+        for args in [
+            ["--keep-last", "3"].as_slice(),
+            &["--keep-days", "7"],
+            &["--size-budget", "50M"],
+            &["--zip-compression", "stored"],
+            &["--zip-compression-level", "9"],
+            &["--zip-file", "static"],
+            &["--rpm"],
+            &["--rpm-release", "2"],
+            &["--codedeploy"],
+            &["--template", "sam"],
+        ] {
+            let opt =
+                Opt::from_args(&["aws-build"], &[&["lambda"], args].concat())
+                    .unwrap();
+            assert!(opt.check_command_options(None).is_ok());
+            assert!(opt.check_command_options(Some("watch")).is_ok());
+            assert!(
+                opt.check_command_options(Some("test")).is_err(),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("123"), Ok(123));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert_eq!(parse_size("50M"), Ok(50 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1024 * 1024 * 1024));
+        for s in ["", "M", "1.5M", "-1", "1T"] {
+            assert!(parse_size(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let contents = "
+            # comment
+            A=1
+
+            B=x=y
+            C=
+        ";
+        assert_eq!(
+            parse_env_file(contents).unwrap(),
+            vec![
+                ("A".into(), "1".into()),
+                ("B".into(), "x=y".into()),
+                ("C".into(), "".into()),
+            ]
+        );
+        assert!(parse_env_file("A").is_err());
+        assert!(parse_env_file("=1").is_err());
+    }
 }
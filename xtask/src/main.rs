@@ -10,6 +10,7 @@ use fs_err as fs;
 use rayon::prelude::*;
 use std::env;
 use std::ffi::OsStr;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 /// Custom tasks.
@@ -49,10 +50,8 @@ struct RunContainerTests {
 fn get_repo_path() -> PathBuf {
     let exe = env::current_exe()?;
     exe.parent()
-        .map(|path| path.parent())
-        .flatten()
-        .map(|path| path.parent())
-        .flatten()
+        .and_then(|path| path.parent())
+        .and_then(|path| path.parent())
         .ok_or_else(|| anyhow!("not enough parents: {}", exe.display()))?
         .into()
 }
@@ -80,7 +79,7 @@ fn make_mock_project(root: &Path, name: &str, deps: &[&str]) {
         r#"fn main() {}
             "#,
     )?;
-    Command::with_args("cargo", &["generate-lockfile"])
+    Command::with_args("cargo", ["generate-lockfile"])
         .set_dir(root)
         .run()?;
 }
@@ -111,6 +110,7 @@ struct Checker<'a> {
     project_name: &'a str,
     project_path: PathBuf,
     code_root: Option<&'a Path>,
+    extra_args: Vec<String>,
 }
 
 impl<'a> Checker<'a> {
@@ -118,11 +118,12 @@ impl<'a> Checker<'a> {
     #[throws]
     fn build(&self, test_input: &TestInput) -> PathBuf {
         let mut cmd =
-            Command::with_args("cargo", &["run", "--bin", "aws-build", "--"]);
+            Command::with_args("cargo", ["run", "--bin", "aws-build", "--"]);
         if let Some(code_root) = self.code_root {
             cmd.add_arg("--code-root");
             cmd.add_arg(code_root);
         }
+        cmd.add_args(&self.extra_args);
         cmd.add_arg(self.mode.as_str());
         cmd.add_arg(&self.project_path);
         cmd.set_dir(&test_input.repo_dir);
@@ -131,7 +132,7 @@ impl<'a> Checker<'a> {
         cmd.log_output_on_error = true;
 
         if let Some(container_cmd) = &test_input.container_cmd {
-            cmd.add_args(&["--container-cmd", container_cmd]);
+            cmd.add_args(["--container-cmd", container_cmd]);
         }
 
         let output = cmd.run()?;
@@ -194,6 +195,7 @@ fn test_al2(test_input: &TestInput) {
         project_name,
         project_path: test_input.test_dir.clone(),
         code_root: None,
+        extra_args: Vec::new(),
     }
     .build_and_check(test_input)?;
 }
@@ -208,6 +210,7 @@ fn test_lambda(test_input: &TestInput) {
         project_name,
         project_path: test_input.test_dir.clone(),
         code_root: None,
+        extra_args: Vec::new(),
     }
     .build_and_check(test_input)?;
 }
@@ -226,6 +229,7 @@ fn test_deps(test_input: &TestInput) {
         project_path: test_input.test_dir.clone(),
         project_name,
         code_root: None,
+        extra_args: Vec::new(),
     }
     .build_and_check(test_input)?;
 }
@@ -275,6 +279,99 @@ fn test_code_root(test_input: &TestInput) {
         code_root: Some(&test_input.test_dir),
         project_name: projects.proj2,
         project_path: projects.proj2_path,
+        extra_args: Vec::new(),
+    }
+    .build_and_check(test_input)?;
+}
+
+/// Test that building a workspace member uses the workspace's target
+/// directory and mounts the workspace root by default, so that a path
+/// dependency on another member works.
+#[throws]
+fn test_workspace(test_input: &TestInput) {
+    let projects = TwoProjects::new(&test_input.test_dir)?;
+    fs::write(
+        test_input.test_dir.join("Cargo.toml"),
+        r#"
+        [workspace]
+        members = ["proj1", "proj2"]
+        "#,
+    )?;
+    Command::with_args("cargo", ["generate-lockfile"])
+        .set_dir(&test_input.test_dir)
+        .run()?;
+
+    let symlink_path = Checker {
+        mode: BuildMode::Al2,
+        code_root: None,
+        project_name: projects.proj2,
+        project_path: projects.proj2_path,
+        extra_args: Vec::new(),
+    }
+    .build(test_input)?;
+    assert_eq!(symlink_path, test_input.test_dir.join("target/latest-al2"));
+}
+
+/// Test that with minimal mounts, files in the code root other than
+/// the workspace's packages and cargo config aren't visible to the
+/// build.
+#[throws]
+fn test_minimal_mounts(test_input: &TestInput) {
+    let projects = TwoProjects::new(&test_input.test_dir)?;
+    fs::write(
+        test_input.test_dir.join("Cargo.toml"),
+        r#"
+        [workspace]
+        members = ["proj1", "proj2"]
+        "#,
+    )?;
+    Command::with_args("cargo", ["generate-lockfile"])
+        .set_dir(&test_input.test_dir)
+        .run()?;
+    fs::write(test_input.test_dir.join("secret.txt"), "secret")?;
+    // The workspace's cargo config must still apply
+    let cargo_dir = test_input.test_dir.join(".cargo");
+    fs::create_dir_all(&cargo_dir)?;
+    fs::write(
+        cargo_dir.join("config.toml"),
+        "[env]\nFROM_WORKSPACE_CONFIG = \"1\"\n",
+    )?;
+    fs::write(
+        projects.proj2_path.join("build.rs"),
+        r#"fn main() {
+                assert!(std::path::Path::new("../Cargo.toml").exists());
+                assert!(std::path::Path::new("../.cargo/config.toml").exists());
+                assert!(!std::path::Path::new("../secret.txt").exists());
+                assert_eq!(
+                    std::env::var("FROM_WORKSPACE_CONFIG").as_deref(),
+                    Ok("1")
+                );
+            }
+            "#,
+    )?;
+
+    Checker {
+        mode: BuildMode::Al2,
+        code_root: None,
+        project_name: projects.proj2,
+        project_path: projects.proj2_path,
+        extra_args: vec!["--minimal-mounts".into()],
+    }
+    .build(test_input)?;
+}
+
+/// Test that the code root is detected from a path dependency outside
+/// the project.
+#[throws]
+fn test_detect_code_root(test_input: &TestInput) {
+    let projects = TwoProjects::new(&test_input.test_dir)?;
+
+    Checker {
+        mode: BuildMode::Al2,
+        code_root: None,
+        project_name: projects.proj2,
+        project_path: projects.proj2_path,
+        extra_args: Vec::new(),
     }
     .build_and_check(test_input)?;
 }
@@ -289,10 +386,281 @@ fn test_bad_project_path(test_input: &TestInput) {
         code_root: Some(&projects.proj1_path),
         project_name: projects.proj2,
         project_path: projects.proj2_path,
+        extra_args: Vec::new(),
     };
     assert!(checker.build_and_check(test_input).is_err());
 }
 
+/// Test that a cargo config file outside the code root is applied to
+/// the build.
+#[throws]
+fn test_cargo_config(test_input: &TestInput) {
+    let project_name = "proj";
+    let project_path = test_input.test_dir.join(project_name);
+    make_mock_project(&project_path, project_name, &[])?;
+
+    // The project only compiles if the config file's rustflags are
+    // used.
+    fs::write(
+        project_path.join("src/main.rs"),
+        r#"#[cfg(not(host_config))]
+            compile_error!("host config not applied");
+            fn main() {}
+            "#,
+    )?;
+    let config_path = test_input.test_dir.join("config.toml");
+    fs::write(
+        &config_path,
+        r#"[build]
+            rustflags = ["--cfg", "host_config"]
+            "#,
+    )?;
+
+    Checker {
+        mode: BuildMode::Al2,
+        project_name,
+        project_path,
+        code_root: None,
+        extra_args: vec![
+            "--cargo-config".into(),
+            config_path.to_str().unwrap().into(),
+        ],
+    }
+    .build_and_check(test_input)?;
+}
+
+/// Test that the pre- and post-build hooks run in the container.
+#[throws]
+fn test_hooks(test_input: &TestInput) {
+    let project_name = "proj";
+    let project_path = test_input.test_dir.join(project_name);
+    make_mock_project(&project_path, project_name, &[])?;
+
+    let mut extra_args = Vec::new();
+    for hook in ["pre-build", "post-build"] {
+        let hook_path = test_input.test_dir.join(format!("{}.sh", hook));
+        fs::write(
+            &hook_path,
+            format!("#!/bin/sh\ntouch \"${{TARGET_DIR}}/{}-ran\"\n", hook),
+        )?;
+        fs::set_permissions(
+            &hook_path,
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        extra_args.push(format!("--{}-hook", hook));
+        extra_args.push(hook_path.to_str().unwrap().into());
+    }
+
+    Checker {
+        mode: BuildMode::Al2,
+        project_name,
+        project_path: project_path.clone(),
+        code_root: None,
+        extra_args,
+    }
+    .build_and_check(test_input)?;
+
+    let output_dir = project_path.join("target/aws-build/al2");
+    assert!(output_dir.join("pre-build-ran").exists());
+    assert!(output_dir.join("post-build-ran").exists());
+}
+
+/// Test that the `test` subcommand runs the tests in the container.
+#[throws]
+fn test_test_command(test_input: &TestInput) {
+    let project_name = "proj";
+    make_mock_project(&test_input.test_dir, project_name, &[])?;
+
+    // This test only passes inside the build container.
+    fs::write(
+        test_input.test_dir.join("src/main.rs"),
+        r#"fn main() {}
+            #[test]
+            fn test_system() {
+                let release = std::fs::read_to_string("/etc/system-release")
+                    .unwrap();
+                assert!(release.starts_with("Amazon Linux"));
+            }
+            "#,
+    )?;
+
+    let mut cmd = Command::with_args(
+        "cargo",
+        ["run", "--bin", "aws-build", "--", "test", "al2"],
+    );
+    cmd.add_arg(&test_input.test_dir);
+    cmd.set_dir(&test_input.repo_dir);
+    if let Some(container_cmd) = &test_input.container_cmd {
+        cmd.add_args(["--container-cmd", container_cmd]);
+    }
+    cmd.run()?;
+}
+
+/// Get the IDs of the running containers kept running by
+/// --reuse-container.
+#[throws]
+fn reused_container_ids(test_input: &TestInput) -> Vec<String> {
+    let mut cmd = Command::from_whitespace_separated_str(
+        test_input.container_cmd.as_deref().unwrap_or("docker"),
+    )
+    .ok_or_else(|| anyhow!("empty container command"))?;
+    cmd.add_args(["ps", "--quiet", "--no-trunc"]);
+    cmd.add_arg_pair("--filter", "name=aws-build-daemon-");
+    cmd.enable_capture();
+    let mut ids: Vec<String> = cmd
+        .run()?
+        .stdout_string_lossy()
+        .lines()
+        .map(Into::into)
+        .collect();
+    ids.sort();
+    ids
+}
+
+/// Test that a second build reuses the container kept running by the
+/// first, and that the `stop` subcommand removes it.
+#[throws]
+fn test_reuse_container(test_input: &TestInput) {
+    let project_name = "proj";
+    let project_path = test_input.test_dir.join(project_name);
+    make_mock_project(&project_path, project_name, &[])?;
+
+    let checker = Checker {
+        mode: BuildMode::Al2,
+        project_name,
+        project_path,
+        code_root: None,
+        extra_args: vec!["--reuse-container".into()],
+    };
+    checker.build_and_check(test_input)?;
+    let ids = reused_container_ids(test_input)?;
+    assert_eq!(ids.len(), 1);
+    checker.build_and_check(test_input)?;
+    assert_eq!(reused_container_ids(test_input)?, ids);
+
+    let mut cmd = Command::with_args(
+        "cargo",
+        ["run", "--bin", "aws-build", "--", "stop"],
+    );
+    cmd.set_dir(&test_input.repo_dir);
+    if let Some(container_cmd) = &test_input.container_cmd {
+        cmd.add_args(["--container-cmd", container_cmd]);
+    }
+    cmd.enable_capture();
+    let output = cmd.run()?;
+    assert!(output.stdout_string_lossy().contains("stopped aws-build-"));
+}
+
+/// Test that the debug info is written to a file next to the output.
+#[throws]
+fn test_split_debug_info(test_input: &TestInput) {
+    let project_name = "proj";
+    make_mock_project(&test_input.test_dir, project_name, &[])?;
+    let checker = Checker {
+        mode: BuildMode::Lambda,
+        project_name,
+        project_path: test_input.test_dir.clone(),
+        code_root: None,
+        extra_args: vec!["--split-debug-info".into()],
+    };
+    checker.build_and_check(test_input)?;
+
+    let real_output_path = fs::canonicalize(checker.build(test_input)?)?;
+    assert!(real_output_path.with_extension("debug").exists());
+}
+
+/// Test that stripping in the container makes the output smaller.
+#[throws]
+fn test_strip(test_input: &TestInput) {
+    let project_name = "proj";
+    make_mock_project(&test_input.test_dir, project_name, &[])?;
+    let mut checker = Checker {
+        mode: BuildMode::Al2,
+        project_name,
+        project_path: test_input.test_dir.clone(),
+        code_root: None,
+        extra_args: Vec::new(),
+    };
+    let unstripped = fs::metadata(checker.build(test_input)?)?.len();
+
+    checker.extra_args = vec!["--strip".into()];
+    checker.build_and_check(test_input)?;
+    let stripped = fs::metadata(checker.build(test_input)?)?.len();
+    assert!(stripped < unstripped);
+}
+
+/// Test that a build larger than the size budget fails.
+#[throws]
+fn test_size_budget(test_input: &TestInput) {
+    let project_name = "proj";
+    make_mock_project(&test_input.test_dir, project_name, &[])?;
+    let mut checker = Checker {
+        mode: BuildMode::Lambda,
+        project_name,
+        project_path: test_input.test_dir.clone(),
+        code_root: None,
+        extra_args: vec!["--size-budget".into(), "100M".into()],
+    };
+    checker.build_and_check(test_input)?;
+
+    checker.extra_args = vec!["--size-budget".into(), "1K".into()];
+    assert!(checker.build(test_input).is_err());
+}
+
+/// Test that an RPM is built next to the AL2 output.
+#[throws]
+fn test_rpm(test_input: &TestInput) {
+    let project_name = "proj";
+    make_mock_project(&test_input.test_dir, project_name, &[])?;
+    let unit_path = test_input.test_dir.join("proj.service");
+    fs::write(&unit_path, "[Service]\nExecStart=/usr/bin/proj\n")?;
+    let checker = Checker {
+        mode: BuildMode::Al2,
+        project_name,
+        project_path: test_input.test_dir.clone(),
+        code_root: None,
+        extra_args: vec![
+            "--rpm".into(),
+            "--rpm-systemd-unit".into(),
+            unit_path.display().to_string(),
+            "--rpm-release".into(),
+            "2".into(),
+        ],
+    };
+    checker.build_and_check(test_input)?;
+
+    let rpm_path = test_input.test_dir.join(format!(
+        "target/aws-build/al2/proj-0.0.0-2.{}.rpm",
+        std::env::consts::ARCH
+    ));
+    assert!(rpm_path.exists());
+}
+
+/// Test that a CodeDeploy bundle is written with the output's name.
+#[throws]
+fn test_codedeploy(test_input: &TestInput) {
+    let project_name = "proj";
+    make_mock_project(&test_input.test_dir, project_name, &[])?;
+    let checker = Checker {
+        mode: BuildMode::Al2,
+        project_name,
+        project_path: test_input.test_dir.clone(),
+        code_root: None,
+        extra_args: vec!["--codedeploy".into()],
+    };
+    checker.build_and_check(test_input)?;
+
+    let real_output_path = fs::canonicalize(checker.build(test_input)?)?;
+    let mut bundle_name = real_output_path.file_name().unwrap().to_owned();
+    bundle_name.push(".zip");
+    let bundle_path = real_output_path
+        .parent()
+        .unwrap()
+        .join("codedeploy")
+        .join(bundle_name);
+    assert!(bundle_path.exists());
+}
+
 type TestFn = fn(&TestInput) -> Result<(), Error>;
 
 const TEST_FUNCS: &[(TestFn, &str)] = &[
@@ -301,6 +669,18 @@ const TEST_FUNCS: &[(TestFn, &str)] = &[
     (test_deps, "test_deps"),
     (test_code_root, "test_code_root"),
     (test_bad_project_path, "test_bad_project_path"),
+    (test_workspace, "test_workspace"),
+    (test_detect_code_root, "test_detect_code_root"),
+    (test_minimal_mounts, "test_minimal_mounts"),
+    (test_cargo_config, "test_cargo_config"),
+    (test_hooks, "test_hooks"),
+    (test_test_command, "test_test_command"),
+    (test_reuse_container, "test_reuse_container"),
+    (test_split_debug_info, "test_split_debug_info"),
+    (test_strip, "test_strip"),
+    (test_size_budget, "test_size_budget"),
+    (test_rpm, "test_rpm"),
+    (test_codedeploy, "test_codedeploy"),
 ];
 
 #[throws]
@@ -331,10 +711,10 @@ fn run_all_tests(args: &RunContainerTests) {
         .filter_map(|(_func, test_name)| {
             let mut cmd = Command::with_args(
                 exe.clone(),
-                &["run-container-tests", "--name", test_name],
+                ["run-container-tests", "--name", test_name],
             );
             if let Some(container_cmd) = &args.container_cmd {
-                cmd.add_args(&["--container-cmd", container_cmd]);
+                cmd.add_args(["--container-cmd", container_cmd]);
             }
             cmd.combine_output = true;
             cmd.capture = true;